@@ -0,0 +1,364 @@
+// Analysis of fn* bodies, done once when the fn* is evaluated rather
+// than every time it is called. Each symbol that names a local (a
+// parameter, or a let*, loop* or catch* binding) is replaced by a
+// Local holding the frame and slot the local will be bound in, so
+// evaluating it doesn't search the env chain by name. Symbols naming
+// anything else are left as they are.
+//
+// Macro calls are expanded here too, so a function's body isn't
+// expanded again each time it is called. Each one becomes an Expanded
+// value holding the call and its analyzed expansion, which is made
+// again where it is next evaluated if a macro has been redefined since.
+// A call to a macro defined after the analysis is analyzed as a
+// function call, which eval recognizes when it is made.
+//
+// Every scope pushed here corresponds to exactly one env created by
+// eval_tco, and slot layouts are found by binding the patterns on a
+// scratch env, so they match the runtime ones by construction.
+
+
+use sync::{Rc, RefCell};
+use types::{MalVal, MalRet, MalVec, MalMap, Symbol, _nil, _true, keyword,
+            listm, vectorm, hash_mapm};
+use types::MalType::{Sym, Local, List, Vector, Hash_Map, MalFunc, Expanded};
+use env::{env_new, env_destructure, env_local_names, Env};
+use eval;
+use namespace;
+
+// The locals visible in each enclosing frame, innermost last
+type Scopes = Vec<Vec<Symbol>>;
+
+// A macro call in an analyzed body
+pub struct Expansion {
+    pub form:  MalVal,  // the call as written
+    scopes:    Scopes,  // the locals visible where it is
+    expansion: RefCell<(u64, MalVal)>,  // macro generation it was made in, and itself
+}
+
+// The analyzed expansion of a macro call, made again in env if a macro
+// has been redefined since it was last made
+pub fn expansion(site: &Expansion, env: &Env) -> MalRet {
+    let generation = eval::macro_generation();
+    {
+        let cached = site.expansion.borrow();
+        if cached.0 == generation {
+            return Ok(cached.1.clone());
+        }
+    }
+    let expanded = eval::macroexpand(site.form.clone(), env.clone())?;
+    let res = analyze(&expanded, &mut site.scopes.clone(), env);
+    *site.expansion.borrow_mut() = (generation, res.clone());
+    Ok(res)
+}
+
+fn marker() -> MalVal {
+    keyword("analyzed")
+}
+
+// Whether a fn* form is the result of analyze_fn
+pub fn is_analyzed(form: &MalVal) -> bool {
+    match **form {
+        List(_, ref meta) => match **meta {
+            Hash_Map(ref hm,_) => hm.contains_key(&marker()),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+// Analyze a (fn* params body) or multi-arity (fn* (params body) ...)
+// form whose closure env is env. Malformed forms are returned as they
+// are, for eval to report.
+pub fn analyze_fn(form: &MalVal, env: &Env) -> MalVal {
+    fn_form(form, &mut vec![], env)
+}
+
+fn fn_form(form: &MalVal, scopes: &mut Scopes, env: &Env) -> MalVal {
+    if is_analyzed(form) {
+        return form.clone();
+    }
+    let (args, meta) = match **form {
+        List(ref args, ref meta) => (args, meta),
+        _ => return form.clone(),
+    };
+    let mut res = MalVec::new();
+    res.push_back(args[0].clone());
+    if !eval::is_multi_arity(args) && args.len() == 3 && is_params(&args[1]) {
+        res.push_back(args[1].clone());
+        res.push_back(fn_body(&args[1], &args[2], scopes, env));
+    } else {
+        for clause in args.iter().skip(1) {
+            let analyzed = match **clause {
+                List(ref c, ref cmeta) if c.len() == 2 && is_params(&c[0]) => {
+                    listm(vec![c[0].clone(), fn_body(&c[0], &c[1], scopes, env)],
+                          cmeta.clone())
+                },
+                _ => return form.clone(),
+            };
+            res.push_back(analyzed);
+        }
+    }
+    listm(res, mark(meta))
+}
+
+fn is_params(params: &MalVal) -> bool {
+    matches!(**params, List(_,_) | Vector(_,_))
+}
+
+fn mark(meta: &MalVal) -> MalVal {
+    let mut hm = match **meta {
+        Hash_Map(ref hm,_) => hm.clone(),
+        _ => MalMap::new(),
+    };
+    hm.insert(marker(), _true());
+    hash_mapm(hm, _nil())
+}
+
+fn fn_body(params: &MalVal, body: &MalVal, scopes: &mut Scopes, env: &Env) -> MalVal {
+    // parameters are bound like the elements of a vector pattern
+    let pattern = match **params {
+        List(ref p,_) | Vector(ref p,_) => vectorm(p.clone(), _nil()),
+        _ => return body.clone(),
+    };
+    let scratch = env_new(None);
+    if env_destructure(&scratch, &pattern, _nil()).is_err() {
+        return body.clone();
+    }
+    scopes.push(env_local_names(&scratch));
+    let res = analyze(body, scopes, env);
+    scopes.pop();
+    res
+}
+
+fn find_local(sym: &Symbol, scopes: &Scopes) -> Option<(usize, usize)> {
+    for (depth, scope) in scopes.iter().rev().enumerate() {
+        if let Some(index) = scope.iter().position(|s| s == sym) {
+            return Some((depth, index));
+        }
+    }
+    None
+}
+
+fn analyze(ast: &MalVal, scopes: &mut Scopes, env: &Env) -> MalVal {
+    match **ast {
+        Sym(ref sym) => match find_local(sym, scopes) {
+            Some((depth, index)) => Rc::new(Local(sym.clone(), depth, index)),
+            None => ast.clone(),
+        },
+        List(ref items, ref meta) => {
+            if items.is_empty() {
+                return ast.clone();
+            }
+            match special_form(ast, items, scopes, env) {
+                Some(res) => res,
+                None => {
+                    if let Sym(ref head) = *items[0] {
+                        if find_local(head, scopes).is_none() && is_macro(&items[0], env) {
+                            return expand(ast, scopes, env);
+                        }
+                    }
+                    listm(analyze_all(items, scopes, env), meta.clone())
+                },
+            }
+        },
+        Vector(ref items, ref meta) => vectorm(analyze_all(items, scopes, env), meta.clone()),
+        Hash_Map(ref hm, ref meta) => {
+            let mut res = MalMap::new();
+            for (k, v) in hm.iter() {
+                res.insert(analyze(k, scopes, env), analyze(v, scopes, env));
+            }
+            hash_mapm(res, meta.clone())
+        },
+        _ => ast.clone(),
+    }
+}
+
+// The first n items as they are, followed by the rest analyzed
+fn analyze_from(n: usize, items: &MalVec, scopes: &mut Scopes, env: &Env) -> MalVec {
+    let mut res: MalVec = items.iter().take(n).cloned().collect();
+    for item in items.iter().skip(n) {
+        res.push_back(analyze(item, scopes, env));
+    }
+    res
+}
+
+fn analyze_all(items: &MalVec, scopes: &mut Scopes, env: &Env) -> MalVec {
+    items.iter().map(|item| analyze(item, scopes, env)).collect()
+}
+
+// A macro call, expanded now. One that fails to expand is left for eval
+// to expand, and report the error of, if it is ever evaluated.
+fn expand(ast: &MalVal, scopes: &mut Scopes, env: &Env) -> MalVal {
+    let generation = eval::macro_generation();
+    let expanded = match eval::macroexpand(ast.clone(), env.clone()) {
+        Ok(expanded) => expanded,
+        Err(_) => return ast.clone(),
+    };
+    let res = analyze(&expanded, scopes, env);
+    Rc::new(Expanded(Rc::new(Expansion{form: ast.clone(), scopes: scopes.clone(),
+                                        expansion: RefCell::new((generation, res))})))
+}
+
+// Whether sym currently names a macro outside the body being analyzed
+fn is_macro(sym: &MalVal, env: &Env) -> bool {
+    match namespace::resolve(env, sym) {
+        Ok(f) => match *f {
+            MalFunc(ref mfd,_) => mfd.is_macro,
+            _ => false,
+        },
+        Err(_) => false,
+    }
+}
+
+// The analyzed form of a special form, or None if items isn't one.
+// Parts that aren't evaluated (quoted forms, def! targets, binding
+// patterns) are kept as they are.
+fn special_form(ast: &MalVal, items: &MalVec, scopes: &mut Scopes, env: &Env) -> Option<MalVal> {
+    let head = match *items[0] {
+        Sym(ref head) => head,
+        _ => return None,
+    };
+    let meta = match **ast {
+        List(_, ref meta) => meta.clone(),
+        _ => _nil(),
+    };
+    Some(match &**head {
+        "quote" | "macroexpand" => ast.clone(),
+        "quasiquote" if items.len() == 2 => {
+            listm(vec![items[0].clone(), quasi(&items[1], scopes, env)], meta)
+        },
+        "def!" | "defmacro!" => listm(analyze_from(2, items, scopes, env), meta),
+        "do" | "if" | "recur" | "eval" => listm(analyze_from(1, items, scopes, env), meta),
+        "fn*" => fn_form(ast, scopes, env),
+        "let*" | "loop*" if items.len() == 3 => {
+            let_form(ast, items, scopes, env)
+        },
+        "binding" if items.len() >= 2 => {
+            let binds = match *items[1] {
+                List(ref b, ref bmeta) | Vector(ref b, ref bmeta) if b.len() % 2 == 0 => {
+                    let mut res = MalVec::new();
+                    for (i, b) in b.iter().enumerate() {
+                        res.push_back(if i % 2 == 0 { b.clone() } else { analyze(b, scopes, env) });
+                    }
+                    vectorm(res, bmeta.clone())
+                },
+                _ => return Some(ast.clone()),
+            };
+            let mut res = analyze_from(2, items, scopes, env);
+            res.set(1, binds);
+            listm(res, meta)
+        },
+        "try*" if items.len() == 3 => {
+            let handler = match *items[2] {
+                List(ref c, ref cmeta) if c.len() == 3 => match *c[1] {
+                    Sym(ref name) => {
+                        scopes.push(vec![name.clone()]);
+                        let body = analyze(&c[2], scopes, env);
+                        scopes.pop();
+                        listm(vec![c[0].clone(), c[1].clone(), body], cmeta.clone())
+                    },
+                    _ => return Some(ast.clone()),
+                },
+                _ => return Some(ast.clone()),
+            };
+            listm(vec![items[0].clone(), analyze(&items[1], scopes, env), handler], meta)
+        },
+        "try*" => listm(analyze_from(1, items, scopes, env), meta),
+        "let*" | "loop*" | "quasiquote" | "binding" => ast.clone(),
+        _ => return None,
+    })
+}
+
+// (let* [pattern init ...] body), where each init sees the locals
+// bound before it
+fn let_form(ast: &MalVal, items: &MalVec, scopes: &mut Scopes, env: &Env) -> MalVal {
+    let (binds, bmeta) = match *items[1] {
+        List(ref b, ref bmeta) | Vector(ref b, ref bmeta) if b.len() % 2 == 0 => (b, bmeta),
+        _ => return ast.clone(),
+    };
+    let scratch = env_new(None);
+    scopes.push(vec![]);
+    let mut res = MalVec::new();
+    let mut it = binds.iter();
+    while let (Some(pat), Some(init)) = (it.next(), it.next()) {
+        let init = analyze(init, scopes, env);
+        if env_destructure(&scratch, pat, _nil()).is_err() {
+            scopes.pop();
+            return ast.clone();
+        }
+        *scopes.last_mut().unwrap() = env_local_names(&scratch);
+        res.push_back(pat.clone());
+        res.push_back(init);
+    }
+    let binds = match *items[1] {
+        List(_,_) => listm(res, bmeta.clone()),
+        _ => vectorm(res, bmeta.clone()),
+    };
+    let body = analyze(&items[2], scopes, env);
+    scopes.pop();
+    let meta = match **ast {
+        List(_, ref meta) => meta.clone(),
+        _ => _nil(),
+    };
+    listm(vec![items[0].clone(), binds, body], meta)
+}
+
+// Only the unquoted parts of a quasiquote template are evaluated
+fn quasi(ast: &MalVal, scopes: &mut Scopes, env: &Env) -> MalVal {
+    match **ast {
+        List(ref items, ref meta) | Vector(ref items, ref meta) => {
+            let unquote = items.len() == 2 && match *items[0] {
+                Sym(ref s) => s == "unquote" || s == "splice-unquote",
+                _ => false,
+            };
+            let res: MalVec = if unquote {
+                vec![items[0].clone(), analyze(&items[1], scopes, env)].into()
+            } else {
+                items.iter().map(|item| quasi(item, scopes, env)).collect()
+            };
+            match **ast {
+                List(_,_) => listm(res, meta.clone()),
+                _ => vectorm(res, meta.clone()),
+            }
+        },
+        _ => ast.clone(),
+    }
+}
+
+// Undo the analysis of a form, for when it turns out to be passed to a
+// macro defined after its fn* was evaluated
+pub fn unanalyze(ast: &MalVal) -> MalVal {
+    if !has_locals(ast) {
+        return ast.clone();
+    }
+    match **ast {
+        Local(ref sym,_,_) => sym.to_val(),
+        Expanded(ref site) => site.form.clone(),
+        List(ref items, ref meta) => {
+            let mut meta = meta.clone();
+            if is_analyzed(ast) {
+                if let Hash_Map(ref hm,_) = *meta.clone() {
+                    meta = hash_mapm(hm.without(&marker()), _nil());
+                }
+            }
+            listm(items.iter().map(unanalyze).collect::<MalVec>(), meta)
+        },
+        Vector(ref items, ref meta) => {
+            vectorm(items.iter().map(unanalyze).collect::<MalVec>(), meta.clone())
+        },
+        Hash_Map(ref hm, ref meta) => {
+            let res = hm.iter().map(|(k, v)| (unanalyze(k), unanalyze(v))).collect();
+            hash_mapm(res, meta.clone())
+        },
+        _ => ast.clone(),
+    }
+}
+
+fn has_locals(ast: &MalVal) -> bool {
+    match **ast {
+        Local(_,_,_) | Expanded(_) => true,
+        List(ref items,_) | Vector(ref items,_) => items.iter().any(has_locals),
+        Hash_Map(ref hm,_) => hm.iter().any(|(k, v)| has_locals(k) || has_locals(v)),
+        _ => false,
+    }
+}
@@ -1,10 +1,9 @@
 extern crate mal;
 
-use std::collections::HashMap;
 
-use mal::types::{MalVal, MalRet, MalError, err_str};
+use mal::types::{MalVal, MalRet, MalMap, MalError, err_str};
 use mal::types::{symbol, _nil, list, vector, hash_map, malfunc};
-use mal::types::MalError::{ErrString, ErrMalVal};
+use mal::types::ErrorKind;
 use mal::types::MalType::{Nil, False, Sym, List, Vector, Hash_Map, Func, MalFunc};
 use mal::{readline, reader, core};
 use mal::env::{env_set, env_get, env_new, env_bind, Env};
@@ -23,16 +22,16 @@ fn eval_ast(ast: MalVal, env: Env) -> MalRet {
             let mut ast_vec : Vec<MalVal> = vec![];
             for mv in a.iter() {
                 let mv2 = mv.clone();
-                ast_vec.push(try!(eval(mv2, env.clone())));
+                ast_vec.push(eval(mv2, env.clone())?);
             }
             Ok(match *ast { List(_,_) => list(ast_vec),
                             _         => vector(ast_vec) })
         }
         Hash_Map(ref hm,_) => {
-            let mut new_hm: HashMap<String,MalVal> = HashMap::new();
+            let mut new_hm = MalMap::new();
             for (key, value) in hm.iter() {
-                new_hm.insert(key.to_string(),
-                              try!(eval(value.clone(), env.clone())));
+                new_hm.insert(key.clone(),
+                              eval(value.clone(), env.clone())?);
             }
             Ok(hash_map(new_hm))
         }
@@ -59,10 +58,10 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
     let tmp = ast;
     let (args, a0sym) = match *tmp {
         List(ref args,_) => {
-            if args.len() == 0 {
+            if args.is_empty() {
                 return Ok(tmp.clone());
             }
-            let ref a0 = *args[0];
+            let a0 = &*args[0];
             match *a0 {
                 Sym(ref a0sym) => (args, &a0sym[..]),
                 _ => (args, "__<fn*>__"),
@@ -75,7 +74,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
         "def!" => {
             let a1 = (*args)[1].clone();
             let a2 = (*args)[2].clone();
-            let r = try!(eval(a2, env.clone()));
+            let r = eval(a2, env.clone())?;
             match *a1 {
                 Sym(_) => {
                     env_set(&env.clone(), a1, r.clone());
@@ -96,7 +95,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                         let exp = it.next().unwrap();
                         match **b {
                             Sym(_) => {
-                                let r = try!(eval(exp.clone(), let_env.clone()));
+                                let r = eval(exp.clone(), let_env.clone())?;
                                 env_set(&let_env, b.clone(), r);
                             },
                             _ => return err_str("let* with non-symbol binding"),
@@ -110,14 +109,14 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
             continue 'tco;
         },
         "do" => {
-            let el = list(args[1..args.len()-1].to_vec());
-            try!(eval_ast(el, env.clone()));
+            let el = list(args.skip(1).take(args.len()-2));
+            eval_ast(el, env.clone())?;
             ast = args[args.len() - 1].clone();
             continue 'tco;
         },
         "if" => {
             let a1 = (*args)[1].clone();
-            let c = try!(eval(a1, env.clone()));
+            let c = eval(a1, env.clone())?;
             match *c {
                 False | Nil => {
                     if args.len() >= 4 {
@@ -139,16 +138,16 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
             return Ok(malfunc(eval, a2, env, a1, _nil()));
         },
         _ => { // function call
-            let el = try!(eval_ast(tmp.clone(), env.clone()));
+            let el = eval_ast(tmp.clone(), env.clone())?;
             let args = match *el {
                 List(ref args,_) => args,
                 _ => return err_str("Invalid apply"),
             };
             return match *args.clone()[0] {
-                Func(f,_) => f(args[1..].to_vec()),
+                Func(ref f,_) => (f.f)(args.iter().skip(1).cloned().collect()),
                 MalFunc(ref mf,_) => {
                     let mfc = mf.clone();
-                    let alst = list(args[1..].to_vec());
+                    let alst = list(args.skip(1));
                     let new_env = env_new(Some(mfc.env.clone()));
                     match env_bind(&new_env, mfc.params, alst) {
                         Ok(_) => {
@@ -173,9 +172,9 @@ fn print(exp: MalVal) -> String {
 }
 
 fn rep(str: &str, env: Env) -> Result<String,MalError> {
-    let ast = try!(read(str.to_string()));
+    let ast = read(str.to_string())?;
     //println!("read: {}", ast);
-    let exp = try!(eval(ast, env));
+    let exp = eval(ast, env)?;
     Ok(print(exp))
 }
 
@@ -192,11 +191,11 @@ fn main() {
     // repl loop
     loop {
         let line = readline::mal_readline("user> ");
-        match line { None => break, _ => () }
+        if line.is_none() { break }
         match rep(&line.unwrap(), repl_env.clone()) {
             Ok(str)  => println!("{}", str),
-            Err(ErrMalVal(_)) => (),  // Blank line
-            Err(ErrString(s)) => println!("Error: {}", s),
+            Err(ref e) if e.kind == ErrorKind::Blank => (),  // Blank line
+            Err(e) => println!("Error: {}", e),
         }
     }
 }
@@ -1,13 +1,12 @@
 
 extern crate mal;
 
-use std::collections::HashMap;
 use std::env as stdenv;
 use std::process as process;
 
-use mal::types::{MalVal, MalRet, MalError, err_str};
+use mal::types::{MalVal, MalRet, MalMap, MalError, err_str};
 use mal::types::{symbol, _nil, string, list, vector, hash_map, malfunc, malfuncd};
-use mal::types::MalError::{ErrString, ErrMalVal};
+use mal::types::ErrorKind;
 use mal::types::MalType::{Nil, False, Sym, List, Vector, Hash_Map, Func, MalFunc};
 use mal::{readline, reader, core};
 use mal::env::{env_set, env_get, env_new, env_bind, env_find, env_root, Env};
@@ -21,7 +20,7 @@ fn read(str: String) -> MalRet {
 // eval
 fn is_pair(x: MalVal) -> bool {
     match *x {
-        List(ref lst,_) | Vector(ref lst,_) => lst.len() > 0,
+        List(ref lst,_) | Vector(ref lst,_) => !lst.is_empty(),
         _ => false,
     }
 }
@@ -33,7 +32,7 @@ fn quasiquote(ast: MalVal) -> MalVal {
 
     match *ast.clone() {
         List(ref args,_) | Vector(ref args,_) => {
-            let ref a0 = args[0];
+            let a0 = &args[0];
             match **a0 {
                 Sym(ref s) if *s == "unquote" => return args[1].clone(),
                 _ => (),
@@ -45,7 +44,7 @@ fn quasiquote(ast: MalVal) -> MalVal {
                             Sym(ref s) if *s == "splice-unquote" => {
                                 return list(vec![symbol("concat"),
                                                  a0args[1].clone(),
-                                                 quasiquote(list(args[1..].to_vec()))])
+                                                 quasiquote(list(args.skip(1)))])
                             },
                             _ => (),
                         }
@@ -53,8 +52,8 @@ fn quasiquote(ast: MalVal) -> MalVal {
                     _ => (),
                 }
             }
-            let rest = list(args[1..].to_vec());
-            return list(vec![symbol("cons"),
+            let rest = list(args.skip(1));
+            list(vec![symbol("cons"),
                              quasiquote(a0.clone()),
                              quasiquote(rest)])
         },
@@ -91,13 +90,13 @@ fn macroexpand(mut ast: MalVal, env: Env) -> MalRet {
             List(ref args,_) => args,
             _ => break,
         };
-        let ref a0 = args[0];
+        let a0 = &args[0];
         let mf = match **a0 {
-            Sym(_) => try!(env_get(&env, &a0)),
+            Sym(_) => env_get(&env, a0)?,
             _ => break,
         };
         match *mf {
-            MalFunc(_,_) => ast = try!(mf.apply(args[1..].to_vec())),
+            MalFunc(_,_) => ast = mf.apply(args.iter().skip(1).cloned().collect())?,
             _ => break,
         }
     }
@@ -111,16 +110,16 @@ fn eval_ast(ast: MalVal, env: Env) -> MalRet {
             let mut ast_vec : Vec<MalVal> = vec![];
             for mv in a.iter() {
                 let mv2 = mv.clone();
-                ast_vec.push(try!(eval(mv2, env.clone())));
+                ast_vec.push(eval(mv2, env.clone())?);
             }
             Ok(match *ast { List(_,_) => list(ast_vec),
                             _         => vector(ast_vec) })
         }
         Hash_Map(ref hm,_) => {
-            let mut new_hm: HashMap<String,MalVal> = HashMap::new();
+            let mut new_hm = MalMap::new();
             for (key, value) in hm.iter() {
-                new_hm.insert(key.to_string(),
-                              try!(eval(value.clone(), env.clone())));
+                new_hm.insert(key.clone(),
+                              eval(value.clone(), env.clone())?);
             }
             Ok(hash_map(new_hm))
         }
@@ -139,7 +138,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
     }
 
     // apply list
-    ast = try!(macroexpand(ast, env.clone()));
+    ast = macroexpand(ast, env.clone())?;
     match *ast {
         List(_,_) => (),  // continue
         _ => return eval_ast(ast, env),
@@ -148,10 +147,10 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
     let tmp = ast;
     let (args, a0sym) = match *tmp {
         List(ref args,_) => {
-            if args.len() == 0 {
+            if args.is_empty() {
                 return Ok(tmp.clone());
             }
-            let ref a0 = *args[0];
+            let a0 = &*args[0];
             match *a0 {
                 Sym(ref a0sym) => (args, &a0sym[..]),
                 _ => (args, "__<fn*>__"),
@@ -164,7 +163,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
         "def!" => {
             let a1 = (*args)[1].clone();
             let a2 = (*args)[2].clone();
-            let r = try!(eval(a2, env.clone()));
+            let r = eval(a2, env.clone())?;
             match *a1 {
                 Sym(_) => {
                     env_set(&env.clone(), a1, r.clone());
@@ -185,7 +184,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                         let exp = it.next().unwrap();
                         match **b {
                             Sym(_) => {
-                                let r = try!(eval(exp.clone(), let_env.clone()));
+                                let r = eval(exp.clone(), let_env.clone())?;
                                 env_set(&let_env, b.clone(), r);
                             },
                             _ => return err_str("let* with non-symbol binding"),
@@ -207,7 +206,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
         "defmacro!" => {
             let a1 = (*args)[1].clone();
             let a2 = (*args)[2].clone();
-            let r = try!(eval(a2, env.clone()));
+            let r = eval(a2, env.clone())?;
             match *r {
                 MalFunc(ref mfd,_) => {
                     match *a1 {
@@ -229,14 +228,14 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
             return macroexpand(a1, env.clone())
         },
         "do" => {
-            let el = list(args[1..args.len()-1].to_vec());
-            try!(eval_ast(el, env.clone()));
+            let el = list(args.skip(1).take(args.len()-2));
+            eval_ast(el, env.clone())?;
             ast = args[args.len() - 1].clone();
             continue 'tco;
         },
         "if" => {
             let a1 = (*args)[1].clone();
-            let c = try!(eval(a1, env.clone()));
+            let c = eval(a1, env.clone())?;
             match *c {
                 False | Nil => {
                     if args.len() >= 4 {
@@ -259,21 +258,21 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
         },
         "eval" => {
             let a1 = (*args)[1].clone();
-            ast = try!(eval(a1, env.clone()));
+            ast = eval(a1, env.clone())?;
             env = env_root(&env);
             continue 'tco;
         },
         _ => { // function call
-            let el = try!(eval_ast(tmp.clone(), env.clone()));
+            let el = eval_ast(tmp.clone(), env.clone())?;
             let args = match *el {
                 List(ref args,_) => args,
                 _ => return err_str("Invalid apply"),
             };
             return match *args.clone()[0] {
-                Func(f,_) => f(args[1..].to_vec()),
+                Func(ref f,_) => (f.f)(args.iter().skip(1).cloned().collect()),
                 MalFunc(ref mf,_) => {
                     let mfc = mf.clone();
-                    let alst = list(args[1..].to_vec());
+                    let alst = list(args.skip(1));
                     let new_env = env_new(Some(mfc.env.clone()));
                     match env_bind(&new_env, mfc.params, alst) {
                         Ok(_) => {
@@ -298,9 +297,9 @@ fn print(exp: MalVal) -> String {
 }
 
 fn rep(str: &str, env: Env) -> Result<String,MalError> {
-    let ast = try!(read(str.to_string()));
+    let ast = read(str.to_string())?;
     //println!("read: {}", ast);
-    let exp = try!(eval(ast, env));
+    let exp = eval(ast, env)?;
     Ok(print(exp))
 }
 
@@ -323,12 +322,12 @@ fn main() {
     let args = stdenv::args();
     if args.len() > 1 {
         let mv_args = args.skip(2)
-            .map(|a| string(a))
+            .map(string)
             .collect::<Vec<MalVal>>();
         env_set(&repl_env, symbol("*ARGV*"), list(mv_args));
         let lf = format!("(load-file \"{}\")",
-                         stdenv::args().skip(1).next().unwrap());
-        return match rep(&lf, repl_env.clone()) {
+                         stdenv::args().nth(1).unwrap());
+        match rep(&lf, repl_env.clone()) {
             Ok(_) => process::exit(0),
             Err(str) => {
                 println!("Error: {:?}", str);
@@ -340,11 +339,11 @@ fn main() {
     // repl loop
     loop {
         let line = readline::mal_readline("user> ");
-        match line { None => break, _ => () }
+        if line.is_none() { break }
         match rep(&line.unwrap(), repl_env.clone()) {
             Ok(str)  => println!("{}", str),
-            Err(ErrMalVal(_)) => (),  // Blank line
-            Err(ErrString(s)) => println!("Error: {}", s),
+            Err(ref e) if e.kind == ErrorKind::Blank => (),  // Blank line
+            Err(e) => println!("Error: {}", e),
         }
     }
 }
@@ -0,0 +1,160 @@
+// Execution budget for untrusted code: a limit on evaluation steps, a
+// wall-clock deadline and an approximate limit on allocation. Running
+// out raises an ErrorKind::Budget error, which try* does not catch.
+//
+// The deadline is checked only every CLOCK_INTERVAL steps, and a call
+// to a native function is a single step however long it runs, so a
+// deadline is noticed once the native call in progress returns.
+// Allocation is charged for the result of every native call, whether
+// made by the evaluator or through apply, map, swap! and the like.
+
+use std::cell::RefCell;
+use std::mem;
+use std::time::{Duration, Instant};
+
+use types::{MalVal, MalType, MalRet, MalError, ErrorKind, string};
+use types::MalType::{Strn, List, Vector, Hash_Map};
+
+/// Limits on the work evaluation may do; `None` means unlimited.
+#[derive(Clone, Debug, Default)]
+pub struct Budget {
+    pub max_steps: Option<u64>,
+    pub timeout:   Option<Duration>,
+    /// Approximate number of bytes of collections and strings
+    pub max_alloc: Option<usize>,
+}
+
+struct State {
+    budget:   Budget,
+    deadline: Option<Instant>,
+    steps:    u64,
+    alloc:    usize,
+}
+
+thread_local! {
+    static STATE: RefCell<Option<State>> = const { RefCell::new(None) };
+}
+
+// Reading the clock is slow compared to a step, so only do it this often
+const CLOCK_INTERVAL: u64 = 256;
+
+// Assume every element of a new collection is a value of its own
+const ELEM_SIZE: usize = mem::size_of::<MalVal>() + mem::size_of::<MalType>();
+
+/// A budget and what has been counted against it, kept by an
+/// `Interpreter` while it isn't evaluating (see `swap`).
+#[derive(Default)]
+pub struct Saved(Option<State>);
+
+impl Saved {
+    // Counting against budget from now
+    pub fn new(budget: &Budget) -> Saved {
+        if budget.max_steps.is_none() &&
+           budget.timeout.is_none() &&
+           budget.max_alloc.is_none() {
+            return Saved(None);
+        }
+        Saved(Some(State{budget: budget.clone(),
+                         deadline: budget.timeout.map(|t| Instant::now() + t),
+                         steps: 0,
+                         alloc: 0}))
+    }
+}
+
+// Start counting against budget from now, replacing any earlier budget
+pub fn set_budget(budget: &Budget) {
+    let Saved(state) = Saved::new(budget);
+    STATE.with(|st| *st.borrow_mut() = state);
+}
+
+// Exchange the current thread's budget with saved
+pub fn swap(saved: &mut Saved) {
+    STATE.with(|st| mem::swap(&mut *st.borrow_mut(), &mut saved.0));
+}
+
+// Whether a budget is being counted against on this thread
+pub fn is_set() -> bool {
+    STATE.with(|st| st.borrow().is_some())
+}
+
+fn exhausted(msg: String) -> MalError {
+    MalError::new(ErrorKind::Budget, string(format!("execution budget exhausted: {}", msg)))
+}
+
+// Count one step of evaluation
+pub fn step() -> Result<(), MalError> {
+    STATE.with(|st| {
+        let mut st = st.borrow_mut();
+        let st = match *st {
+            Some(ref mut st) => st,
+            None => return Ok(()),
+        };
+        st.steps += 1;
+        if let Some(max) = st.budget.max_steps {
+            if st.steps > max {
+                return Err(exhausted(format!("step limit of {} exceeded", max)));
+            }
+        }
+        if let (Some(deadline), Some(timeout)) = (st.deadline, st.budget.timeout) {
+            if st.steps % CLOCK_INTERVAL == 0 && Instant::now() >= deadline {
+                return Err(exhausted(format!("time limit of {}ms exceeded",
+                                             timeout.as_secs() * 1000 +
+                                             timeout.subsec_nanos() as u64 / 1000000)));
+            }
+        }
+        Ok(())
+    })
+}
+
+fn size_of_val(mv: &MalVal) -> usize {
+    match **mv {
+        Strn(ref s) => s.len(),
+        List(ref v,_) | Vector(ref v,_) => v.len() * ELEM_SIZE,
+        Hash_Map(ref hm,_) => hm.len() * 2 * ELEM_SIZE,
+        _ => 0,
+    }
+}
+
+// Size of the largest argument to a native function, which its result
+// may share structure with, or None if allocation isn't being limited
+fn alloc_base(args: &[MalVal]) -> Option<usize> {
+    let limited = STATE.with(|st| match *st.borrow() {
+        Some(ref st) => st.budget.max_alloc.is_some(),
+        None => false,
+    });
+    if !limited {
+        return None;
+    }
+    Some(args.iter().map(size_of_val).max().unwrap_or(0))
+}
+
+// Call a native function, charging its result against the allocation
+// limit
+pub fn charged<F>(f: F, args: Vec<MalVal>) -> MalRet
+    where F: FnOnce(Vec<MalVal>) -> MalRet
+{
+    let base = alloc_base(&args);
+    f(args).and_then(|res| charge_growth(base, res))
+}
+
+// Charge a native function's result for what it added beyond base
+fn charge_growth(base: Option<usize>, res: MalVal) -> MalRet {
+    let base = match base {
+        Some(base) => base,
+        None => return Ok(res),
+    };
+    let growth = size_of_val(&res).saturating_sub(base);
+    STATE.with(|st| {
+        let mut st = st.borrow_mut();
+        if let Some(ref mut st) = *st {
+            st.alloc = st.alloc.saturating_add(growth);
+            if let Some(max) = st.budget.max_alloc {
+                if st.alloc > max {
+                    return Err(exhausted(format!("allocation limit of {} bytes exceeded", max)));
+                }
+            }
+        }
+        Ok(())
+    })?;
+    Ok(res)
+}
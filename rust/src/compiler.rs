@@ -0,0 +1,657 @@
+// Compiler from mal forms to bytecode for the VM (see vm).
+//
+// A form is compiled with its macros expanded, special forms turned
+// into jumps and environment operations, and locals resolved to the
+// frame and slot they are bound in, as the analyzer does for the
+// tree-walker. Variables still live in Envs, so closures, namespaces
+// and dynamic vars work the same in both evaluators.
+//
+// Each fn* body is compiled to a Proto of its own, which a MalFunc
+// made by the VM holds as a Code value in place of the body form.
+// Parts of a form that the VM runs nested rather than inline (try*
+// bodies and handlers, binding bodies) are Protos too.
+//
+// Macros are expanded as they are defined when the form is compiled
+// (vm::eval compiles the subforms of a top-level do or if separately,
+// as each is reached).
+// A fn* body is compiled again when it is next called after a macro
+// has been redefined (see eval::macro_generation), and calls to a
+// macro defined after it was compiled are caught by MacroCheck. A
+// loop that recurs after a macro has been redefined carries on in its
+// Proto compiled again, whether or not it is in a fn* body.
+
+
+use sync::{Rc, RefCell};
+use types::{MalVal, MalVec, MalError, Symbol, Pos, form_pos, _nil, list, vectorm,
+            hash_mapm, string};
+use types::MalType::{Sym, Local, List, Vector, Hash_Map, MalFunc, Code};
+use env::{env_new, env_outer, env_destructure, env_local_names, Env};
+use eval;
+use namespace;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Const(usize),                 // push consts[i]
+    Local(usize, usize, usize),   // push a local: depth, index, consts[i] naming it
+    Global(usize),                // push the value of the symbol consts[i]
+    Vector(usize),                // pop n values, push them as a vector
+    Map(usize),                   // pop n key/value pairs, push them as a hash-map
+    Closure(usize),               // push fns[i] closed over the env
+    Pop,
+    Jump(usize),
+    JumpIfFalse(usize),           // pop, and jump if nil or false
+    // Fall back to evaluating the call form consts[i] with the
+    // tree-walker and jump to pc if the global below turns out to be a
+    // macro, defined after the form was compiled
+    MacroCheck(usize, usize),
+    Call(usize),                  // call the function below n arguments
+    TailCall(usize),
+    Return,
+    PushEnv,                      // evaluate in a new child env
+    PopEnv,
+    Bind(usize),                  // pop, and bind the binding form consts[i]
+    // Pop n arguments, rebind the recur target loops[i] with them in a
+    // new child of the env outside the frame depth levels out, and jump
+    Recur(usize, usize, usize),
+    Def(usize, usize),            // def! consts[i] with metadata consts[j]
+    DefMacro(usize),
+    // Run subs[i] in the current env, and if it fails run the handler
+    // subs[j], if any, in a child env binding consts[k] to the error
+    Try(usize, Option<(usize, usize)>),
+    Dynamic(usize),               // check that consts[i] names a dynamic var
+    Binding(usize, usize),        // pop values for the vars listed in consts[i] around subs[j]
+    Eval,
+    Macroexpand(usize),
+    Interpret(usize),             // evaluate consts[i] with the tree-walker
+    Fail(usize),                  // raise the error message consts[i]
+}
+
+// A fn* the VM can make closures from
+pub struct FnTemplate {
+    pub params:  MalVal,
+    pub exp:     MalVal,  // a Code value
+    pub arities: Vec<(MalVal, MalVal)>,
+}
+
+pub struct Proto {
+    pub code:    Vec<Op>,
+    pub consts:  Vec<MalVal>,
+    pub fns:     Vec<FnTemplate>,
+    pub subs:    Vec<Rc<Proto>>,
+    pub loops:   Vec<Loop>,  // recur targets
+    positions:   Vec<Pos>,
+    op_pos:      Vec<u32>,  // index into positions of each op, if any
+    op_nest:     Vec<usize>,  // Ctx nest of each op
+    pub source:  MalVal,  // the form compiled, for printing
+    generation:  u64,  // of macro definitions it was compiled with
+    origin:      Origin,  // what it was compiled from
+    recompiled:  RefCell<Option<Rc<Proto>>>,
+}
+
+// Where the source of a Proto is, to compile it again from
+struct Origin {
+    params: Option<MalVal>,  // if it is a fn* body
+    scopes: Scopes,  // the locals visible outside it
+    pos:    Option<Pos>,
+}
+
+// The binding forms of a loop* (or the parameters of a fn*), where its
+// body starts, and how many envs below the one the Proto was compiled
+// for its locals are bound
+pub type Loop = (MalVal, usize, usize);
+
+const NO_POS: u32 = u32::MAX;
+
+impl Proto {
+    // Position of the innermost form with a known position that the
+    // op at pc belongs to
+    pub fn pos(&self, pc: usize) -> Option<Pos> {
+        match self.op_pos.get(pc) {
+            Some(&i) if i != NO_POS => Some(self.positions[i as usize].clone()),
+            _ => None,
+        }
+    }
+
+    // How many levels deeper than the Proto the tree-walker would be
+    // nested running the op at pc, which the VM counts towards the
+    // depth limit the same way
+    pub fn nest(&self, pc: usize) -> usize {
+        self.op_nest[pc]
+    }
+}
+
+// The Proto to run in env, compiled again with macros looked up in env
+// if one has been redefined since it was compiled
+pub fn current(proto: &Rc<Proto>, env: &Env) -> Rc<Proto> {
+    let generation = eval::macro_generation();
+    if proto.generation == generation {
+        return proto.clone();
+    }
+    if let Some(ref p) = *proto.recompiled.borrow() {
+        if p.generation == generation {
+            return p.clone();
+        }
+    }
+    let origin = &proto.origin;
+    let p = match origin.params {
+        Some(ref params) => match compile_body(env, params, &proto.source, &origin.scopes,
+                                               origin.pos.clone()) {
+            Some(p) => p,
+            None => return proto.clone(),
+        },
+        None => compile_form(env, &proto.source, &origin.scopes, origin.pos.clone()),
+    };
+    *proto.recompiled.borrow_mut() = Some(p.clone());
+    p
+}
+
+// The Proto to carry on running a loop of proto in, when recurring to
+// loops[target] with its locals bound in loop_env: proto compiled again
+// if a macro has been redefined since it was compiled, provided that
+// its code up to the loop is unchanged
+pub fn resume(proto: &Rc<Proto>, target: usize, loop_env: &Env) -> Option<Rc<Proto>> {
+    if proto.generation == eval::macro_generation() {
+        return None;
+    }
+    let (ref params, start, levels) = proto.loops[target];
+    let mut env = loop_env.clone();
+    for _ in 0..levels {
+        env = env_outer(&env).expect("loop outside the env its Proto is run in");
+    }
+    let p = current(proto, &env);
+    if Rc::ptr_eq(&p, proto) {
+        return None;
+    }
+    match p.loops.get(target) {
+        Some(&(ref p_params, p_start, _)) if p_start == start && p_params == params &&
+                                             p.code[..start] == proto.code[..start] => Some(p),
+        _ => None,
+    }
+}
+
+// The locals visible in each enclosing frame, innermost last
+type Scopes = Vec<Vec<Symbol>>;
+
+// Where a form is being compiled: whether its value is the value of
+// the Proto, the recur target it is in tail position for, if any (with
+// the number of scopes there are at the target's frame), and how many
+// levels deeper than the Proto's the tree-walker would be nested
+// evaluating it
+#[derive(Clone, Copy)]
+struct Ctx {
+    tail:  bool,
+    recur: Option<(usize, usize)>,
+    nest:  usize,
+}
+
+const TAIL: Ctx = Ctx{tail: true, recur: None, nest: 0};
+
+impl Ctx {
+    // A subform whose value the form uses
+    fn value(&self) -> Ctx {
+        Ctx{tail: false, recur: None, nest: self.nest + 1}
+    }
+}
+
+struct Builder<'a> {
+    env:       &'a Env,
+    code:      Vec<Op>,
+    consts:    Vec<MalVal>,
+    fns:       Vec<FnTemplate>,
+    subs:      Vec<Rc<Proto>>,
+    loops:     Vec<Loop>,
+    base:      usize,  // scopes outside the Proto
+    positions: Vec<Pos>,
+    op_pos:    Vec<u32>,
+    op_nest:   Vec<usize>,
+    cur_pos:   u32,
+    cur_nest:  usize,
+    generation: u64,
+}
+
+// Compile a form to be evaluated in env (at the top level of a REPL,
+// file or eval), macros being looked up in env
+pub fn compile(ast: &MalVal, env: &Env) -> Rc<Proto> {
+    compile_form(env, ast, &vec![], None)
+}
+
+impl<'a> Builder<'a> {
+    fn new(env: &'a Env, scopes: &Scopes, pos: Option<Pos>) -> Builder<'a> {
+        let mut b = Builder{env, code: vec![], consts: vec![], fns: vec![], subs: vec![],
+                            loops: vec![], base: scopes.len(), positions: vec![], op_pos: vec![],
+                            op_nest: vec![], cur_pos: NO_POS, cur_nest: 0,
+                            generation: eval::macro_generation()};
+        if let Some(pos) = pos {
+            b.positions.push(pos);
+            b.cur_pos = 0;
+        }
+        b
+    }
+
+    fn finish(mut self, source: MalVal, origin: Origin) -> Rc<Proto> {
+        self.emit(Op::Return);
+        Rc::new(Proto{code: self.code, consts: self.consts, fns: self.fns,
+                      subs: self.subs, loops: self.loops, positions: self.positions,
+                      op_pos: self.op_pos, op_nest: self.op_nest, source,
+                      generation: self.generation, origin,
+                      recompiled: RefCell::new(None)})
+    }
+
+    // Where the form being compiled is, if known
+    fn cur_pos(&self) -> Option<Pos> {
+        if self.cur_pos == NO_POS {
+            None
+        } else {
+            Some(self.positions[self.cur_pos as usize].clone())
+        }
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        self.code.push(op);
+        self.op_pos.push(self.cur_pos);
+        self.op_nest.push(self.cur_nest);
+        self.code.len() - 1
+    }
+
+    fn konst(&mut self, val: MalVal) -> usize {
+        self.consts.push(val);
+        self.consts.len() - 1
+    }
+
+    // Point the jump at pc to the next op
+    fn patch(&mut self, pc: usize) {
+        let target = self.code.len();
+        self.code[pc] = match self.code[pc] {
+            Op::Jump(_) => Op::Jump(target),
+            Op::JumpIfFalse(_) => Op::JumpIfFalse(target),
+            Op::MacroCheck(form, _) => Op::MacroCheck(form, target),
+            op => op,
+        };
+    }
+
+    // Leave the value of the form on the stack, or return it if ctx
+    // is a tail position
+    fn form(&mut self, ast: &MalVal, scopes: &mut Scopes, ctx: Ctx) {
+        // as in the tree-walker, errors are reported at the innermost
+        // list with a known position
+        let (saved_pos, saved_nest) = (self.cur_pos, self.cur_nest);
+        if let (&List(_,_), Some(pos)) = (&**ast, form_pos(ast)) {
+            self.positions.push(pos);
+            self.cur_pos = (self.positions.len() - 1) as u32;
+        }
+        self.cur_nest = ctx.nest;
+        self.value(ast, scopes, ctx);
+        self.cur_pos = saved_pos;
+        self.cur_nest = saved_nest;
+    }
+
+    fn value(&mut self, ast: &MalVal, scopes: &mut Scopes, ctx: Ctx) {
+        match **ast {
+            Sym(ref sym) | Local(ref sym,_,_) => self.symbol(sym, scopes),
+            List(ref items,_) if !items.is_empty() => return self.list(ast, items, scopes, ctx),
+            Vector(ref items,_) if is_constant(items.iter()) => {
+                let k = self.konst(vectorm(items.clone(), _nil()));
+                self.emit(Op::Const(k));
+            },
+            Vector(ref items,_) => {
+                for item in items.iter() {
+                    self.form(item, scopes, ctx.value());
+                }
+                self.emit(Op::Vector(items.len()));
+            },
+            Hash_Map(ref hm,_) if is_constant(hm.iter().flat_map(|(k, v)| vec![k, v])) => {
+                let k = self.konst(hash_mapm(hm.clone(), _nil()));
+                self.emit(Op::Const(k));
+            },
+            Hash_Map(ref hm,_) => {
+                for (k, v) in hm.iter() {
+                    self.form(k, scopes, ctx.value());
+                    self.form(v, scopes, ctx.value());
+                }
+                self.emit(Op::Map(hm.len()));
+            },
+            _ => {
+                let k = self.konst(ast.clone());
+                self.emit(Op::Const(k));
+            },
+        }
+        if ctx.tail {
+            self.emit(Op::Return);
+        }
+    }
+
+    fn symbol(&mut self, sym: &Symbol, scopes: &Scopes) {
+        let k = self.konst(sym.to_val());
+        match find_local(sym, scopes) {
+            Some((depth, index)) => self.emit(Op::Local(depth, index, k)),
+            None => self.emit(Op::Global(k)),
+        };
+    }
+
+    // Have the tree-walker evaluate a form the compiler can't, so that
+    // it raises whatever error the form calls for when evaluated
+    fn interpret(&mut self, ast: &MalVal, ctx: Ctx) {
+        let k = self.konst(ast.clone());
+        self.emit(Op::Interpret(k));
+        if ctx.tail {
+            self.emit(Op::Return);
+        }
+    }
+
+    fn list(&mut self, ast: &MalVal, items: &MalVec, scopes: &mut Scopes, ctx: Ctx) {
+        let head = match *items[0] {
+            Sym(ref head) => Some(head.clone()),
+            _ => None,
+        };
+        let head = match head {
+            Some(head) => head,
+            None => return self.call(ast, items, false, scopes, ctx),
+        };
+        let local = find_local(&head, scopes).is_some();
+        if !local && is_macro(&items[0], self.env) {
+            return match eval::macroexpand(ast.clone(), self.env.clone()) {
+                Ok(expanded) => self.form(&expanded, scopes, ctx),
+                Err(_) => self.interpret(ast, ctx),
+            };
+        }
+        if eval::check_syntax(&head, items).is_err() {
+            return self.interpret(ast, ctx);
+        }
+        match &*head {
+            "def!" => {
+                let (sym, meta) = match eval::def_target(&items[1]) {
+                    Some(target) => target,
+                    None => return self.interpret(ast, ctx),
+                };
+                self.form(&items[2], scopes, ctx.value());
+                let (s, m) = (self.konst(sym), self.konst(meta));
+                self.emit(Op::Def(s, m));
+            },
+            "defmacro!" => {
+                self.form(&items[2], scopes, ctx.value());
+                let s = self.konst(items[1].clone());
+                self.emit(Op::DefMacro(s));
+            },
+            "let*" | "loop*" => return self.let_form(ast, items, &head == "loop*", scopes, ctx),
+            "recur" => {
+                for arg in items.iter().skip(1) {
+                    self.form(arg, scopes, ctx.value());
+                }
+                match ctx.recur {
+                    Some((target, target_scopes)) => {
+                        self.emit(Op::Recur(items.len() - 1, scopes.len() - target_scopes,
+                                            target));
+                    },
+                    _ => {
+                        let k = self.konst(string("Can only recur from tail position".to_string()));
+                        self.emit(Op::Fail(k));
+                    },
+                }
+                return;
+            },
+            "quote" => {
+                let k = self.konst(items[1].clone());
+                self.emit(Op::Const(k));
+            },
+            "quasiquote" => return match eval::quasiquote(items[1].clone()) {
+                Ok(expanded) => self.form(&expanded, scopes, ctx),
+                Err(_) => self.interpret(ast, ctx),
+            },
+            "macroexpand" => {
+                let k = self.konst(items[1].clone());
+                self.emit(Op::Macroexpand(k));
+            },
+            "try*" => {
+                let body = self.sub(&items[1], scopes);
+                let handler = match items.get(2).map(|h| &**h) {
+                    Some(List(ref c,_)) => {
+                        let sym = match *c[1] {
+                            Sym(ref sym) => sym.clone(),
+                            _ => return self.interpret(ast, ctx),
+                        };
+                        scopes.push(vec![sym]);
+                        let h = self.sub(&c[2], scopes);
+                        scopes.pop();
+                        Some((h, self.konst(c[1].clone())))
+                    },
+                    _ => None,
+                };
+                self.emit(Op::Try(body, handler));
+            },
+            "binding" => {
+                let binds = match *items[1] {
+                    List(ref binds,_) | Vector(ref binds,_) => binds.clone(),
+                    _ => return self.interpret(ast, ctx),
+                };
+                let mut vars = vec![];
+                let mut it = binds.iter();
+                while let (Some(var), Some(init)) = (it.next(), it.next()) {
+                    let k = self.konst(var.clone());
+                    self.emit(Op::Dynamic(k));
+                    self.form(init, scopes, ctx.value());
+                    vars.push(var.clone());
+                }
+                let mut body = vec![Symbol::new("do").to_val()];
+                body.extend(items.iter().skip(2).cloned());
+                let body = self.sub(&list(body), scopes);
+                let k = self.konst(list(vars));
+                self.emit(Op::Binding(k, body));
+            },
+            "do" => {
+                if items.len() == 1 {
+                    return self.value(&_nil(), scopes, ctx);
+                }
+                for (i, item) in items.iter().enumerate().skip(1) {
+                    if i == items.len() - 1 {
+                        return self.form(item, scopes, ctx);
+                    }
+                    self.form(item, scopes, ctx.value());
+                    self.emit(Op::Pop);
+                }
+            },
+            "if" => {
+                self.form(&items[1], scopes, ctx.value());
+                let to_else = self.emit(Op::JumpIfFalse(0));
+                self.form(&items[2], scopes, ctx);
+                let to_end = if ctx.tail { None } else { Some(self.emit(Op::Jump(0))) };
+                self.patch(to_else);
+                match items.get(3) {
+                    Some(alt) => self.form(alt, scopes, ctx),
+                    None => self.value(&_nil(), scopes, ctx),
+                }
+                if let Some(pc) = to_end {
+                    self.patch(pc);
+                }
+                return;
+            },
+            "fn*" => {
+                match self.fn_form(items, scopes) {
+                    Some(template) => {
+                        self.fns.push(template);
+                        let i = self.fns.len() - 1;
+                        self.emit(Op::Closure(i));
+                    },
+                    None => return self.interpret(ast, ctx),
+                }
+            },
+            "eval" => {
+                self.form(&items[1], scopes, ctx.value());
+                self.emit(Op::Eval);
+            },
+            _ => return self.call(ast, items, !local, scopes, ctx),
+        }
+        if ctx.tail {
+            self.emit(Op::Return);
+        }
+    }
+
+    fn call(&mut self, ast: &MalVal, items: &MalVec, global: bool, scopes: &mut Scopes, ctx: Ctx) {
+        self.form(&items[0], scopes, ctx.value());
+        let check = if global {
+            let k = self.konst(ast.clone());
+            Some(self.emit(Op::MacroCheck(k, 0)))
+        } else {
+            None
+        };
+        for arg in items.iter().skip(1) {
+            self.form(arg, scopes, ctx.value());
+        }
+        if ctx.tail {
+            self.emit(Op::TailCall(items.len() - 1));
+            if let Some(pc) = check {
+                self.patch(pc);
+                self.emit(Op::Return);
+            }
+        } else {
+            self.emit(Op::Call(items.len() - 1));
+            if let Some(pc) = check {
+                self.patch(pc);
+            }
+        }
+    }
+
+    // (let* [pattern init ...] body) or (loop* ...), where each init
+    // sees the locals bound before it
+    fn let_form(&mut self, ast: &MalVal, items: &MalVec, is_loop: bool,
+                scopes: &mut Scopes, ctx: Ctx) {
+        let binds = match *items[1] {
+            List(ref binds,_) | Vector(ref binds,_) => binds.clone(),
+            _ => return self.interpret(ast, ctx),
+        };
+        let scratch = env_new(None);
+        let mut patterns = vec![];
+        self.emit(Op::PushEnv);
+        scopes.push(vec![]);
+        let mut it = binds.iter();
+        while let (Some(pat), Some(init)) = (it.next(), it.next()) {
+            self.form(init, scopes, ctx.value());
+            let k = self.konst(pat.clone());
+            self.emit(Op::Bind(k));
+            // a pattern that can't be bound fails at run time; there is
+            // nothing to resolve in the rest of the form
+            if env_destructure(&scratch, pat, _nil()).is_err() {
+                scopes.pop();
+                return self.fail_after_bind(ctx);
+            }
+            *scopes.last_mut().unwrap() = env_local_names(&scratch);
+            patterns.push(pat.clone());
+        }
+        let body_ctx = if is_loop {
+            self.loops.push((list(patterns), self.code.len(), scopes.len() - self.base));
+            Ctx{recur: Some((self.loops.len() - 1, scopes.len())), ..ctx}
+        } else {
+            ctx
+        };
+        if ctx.tail {
+            self.form(&items[2], scopes, body_ctx);
+        } else {
+            self.form(&items[2], scopes, Ctx{tail: false, ..body_ctx});
+            self.emit(Op::PopEnv);
+        }
+        scopes.pop();
+    }
+
+    // Code following a Bind that is known to fail, which is never run
+    fn fail_after_bind(&mut self, ctx: Ctx) {
+        let k = self.konst(string("unsupported binding form".to_string()));
+        self.emit(Op::Fail(k));
+        if ctx.tail {
+            self.emit(Op::Return);
+        }
+    }
+
+    // A Proto for part of the form that the VM runs nested in the
+    // current env
+    fn sub(&mut self, ast: &MalVal, scopes: &mut Scopes) -> usize {
+        let sub = compile_form(self.env, ast, scopes, self.cur_pos());
+        self.subs.push(sub);
+        self.subs.len() - 1
+    }
+
+    fn fn_form(&mut self, items: &MalVec, scopes: &mut Scopes) -> Option<FnTemplate> {
+        if eval::is_multi_arity(items) {
+            let clauses = match eval::arities(items) {
+                Ok(clauses) => clauses,
+                Err(_) => return None,
+            };
+            let mut arities = vec![];
+            for (params, body) in clauses {
+                match self.fn_body(&params, &body, scopes) {
+                    Some(code) => arities.push((params, code)),
+                    None => return None,
+                }
+            }
+            let (params, exp) = arities[0].clone();
+            Some(FnTemplate{params, exp, arities})
+        } else {
+            self.fn_body(&items[1], &items[2], scopes).map(|code| {
+                FnTemplate{params: items[1].clone(), exp: code, arities: vec![]}
+            })
+        }
+    }
+
+    fn fn_body(&mut self, params: &MalVal, body: &MalVal, scopes: &Scopes) -> Option<MalVal> {
+        compile_body(self.env, params, body, scopes, self.cur_pos())
+            .map(|proto| Rc::new(Code(proto)))
+    }
+}
+
+// Compile a form at pos to run as a Proto of its own, whose enclosing
+// locals are scopes
+fn compile_form(env: &Env, ast: &MalVal, scopes: &Scopes, pos: Option<Pos>) -> Rc<Proto> {
+    let mut b = Builder::new(env, scopes, pos.clone());
+    b.form(ast, &mut scopes.clone(), TAIL);
+    b.finish(ast.clone(), Origin{params: None, scopes: scopes.clone(), pos})
+}
+
+// Compile the body of a fn* at pos, whose enclosing locals are scopes
+fn compile_body(env: &Env, params: &MalVal, body: &MalVal, scopes: &Scopes,
+                pos: Option<Pos>) -> Option<Rc<Proto>> {
+    // parameters are bound like the elements of a vector pattern
+    let pattern = match **params {
+        List(ref p,_) | Vector(ref p,_) => vectorm(p.clone(), _nil()),
+        _ => return None,
+    };
+    let scratch = env_new(None);
+    if env_destructure(&scratch, &pattern, _nil()).is_err() {
+        return None;
+    }
+    let mut inner = scopes.clone();
+    inner.push(env_local_names(&scratch));
+    let mut b = Builder::new(env, scopes, pos.clone());
+    b.loops.push((params.clone(), 0, 1));
+    let ctx = Ctx{recur: Some((0, inner.len())), ..TAIL};
+    b.form(body, &mut inner, ctx);
+    Some(b.finish(body.clone(), Origin{params: Some(params.clone()), scopes: scopes.clone(),
+                                       pos}))
+}
+
+// Whether evaluating each of the forms gives the form itself
+fn is_constant<'b, I: Iterator<Item=&'b MalVal>>(mut forms: I) -> bool {
+    forms.all(|f| !matches!(**f, Sym(_) | Local(_,_,_) | List(_,_) | Vector(_,_) | Hash_Map(_,_)))
+}
+
+fn find_local(sym: &Symbol, scopes: &Scopes) -> Option<(usize, usize)> {
+    for (depth, scope) in scopes.iter().rev().enumerate() {
+        if let Some(index) = scope.iter().position(|s| s == sym) {
+            return Some((depth, index));
+        }
+    }
+    None
+}
+
+// Whether sym currently names a macro
+pub fn is_macro(sym: &MalVal, env: &Env) -> bool {
+    match namespace::resolve(env, sym) {
+        Ok(f) => match *f {
+            MalFunc(ref mfd,_) => mfd.is_macro,
+            _ => false,
+        },
+        Err(_) => false,
+    }
+}
+
+// The error a Fail op raises
+pub fn failure(proto: &Proto, k: usize) -> MalError {
+    MalError::runtime(proto.consts[k].pr_str(false))
+}
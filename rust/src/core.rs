@@ -1,20 +1,26 @@
 #![allow(dead_code)]
 
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::prelude::*;
+use std::cmp::{self, Ordering};
+use std::f64;
 //use std::num::ToPrimitive;
 use num::traits::ToPrimitive;
+use num::{BigInt, BigRational, Zero};
 use time;
 
-use types::{MalVal,MalRet,err_val,err_str,err_string,
-            _nil,_true,_false,_int,string,
-            list,vector,listm,vectorm,hash_mapm,func,funcm,malfuncd};
-use types::MalType::{Nil, Int, Strn, List, Vector, Hash_Map, Func, MalFunc, Atom};
+use sync::{Rc, RefCell};
+use types::{MalVal,MalRet,MalVec,MalMap,MalError,MalType,err_val,err_str,err_string,
+            _nil,_true,_false,_int,string,keyword,
+            list,vector,listm,vectorm,hash_mapm,native_fn,funcd,malfuncd};
+use types::MalType::{Nil, Int, Strn, List, Vector, Hash_Map, Func, MalFunc, Atom, ExInfo};
 use types;
 use readline;
 use reader;
 use printer;
+use sandbox::Capabilities;
+use gc;
+#[cfg(feature = "threads")]
+use threads;
 
 // General functions
 fn equal_q(a: Vec<MalVal>) -> MalRet {
@@ -32,6 +38,54 @@ fn throw(a: Vec<MalVal>) -> MalRet {
     err_val(a[0].clone())
 }
 
+fn ex_info(a: Vec<MalVal>) -> MalRet {
+    if a.len() < 2 || a.len() > 3 {
+        return err_str("Wrong arity to ex-info call");
+    }
+    let msg = match *a[0] {
+        Strn(ref s) => s.to_string(),
+        _ => return err_str("ex-info called with non-string message"),
+    };
+    match *a[1] {
+        Hash_Map(_,_) => (),
+        _ => return err_str("ex-info called with non-map data"),
+    }
+    let cause = if a.len() == 3 { a[2].clone() } else { _nil() };
+    Ok(types::ex_info(msg, a[1].clone(), cause))
+}
+
+// Errors raised by the interpreter are caught as their message string
+fn ex_message(a: Vec<MalVal>) -> MalRet {
+    if a.len() != 1 {
+        return err_str("Wrong arity to ex-message call");
+    }
+    match *a[0] {
+        ExInfo(ref msg,_,_) => Ok(string(msg.clone())),
+        Strn(_) => Ok(a[0].clone()),
+        _ => Ok(_nil()),
+    }
+}
+
+fn ex_data(a: Vec<MalVal>) -> MalRet {
+    if a.len() != 1 {
+        return err_str("Wrong arity to ex-data call");
+    }
+    match *a[0] {
+        ExInfo(_,ref data,_) => Ok(data.clone()),
+        _ => Ok(_nil()),
+    }
+}
+
+fn ex_cause(a: Vec<MalVal>) -> MalRet {
+    if a.len() != 1 {
+        return err_str("Wrong arity to ex-cause call");
+    }
+    match *a[0] {
+        ExInfo(_,_,ref cause) => Ok(cause.clone()),
+        _ => Ok(_nil()),
+    }
+}
+
 // String routines
 fn pr_str(a: Vec<MalVal>) -> MalRet {
     Ok(string(printer::pr_list(&a, true, "", "", " ")))
@@ -41,19 +95,23 @@ fn str(a: Vec<MalVal>) -> MalRet {
     Ok(string(printer::pr_list(&a, false, "", "", "")))
 }
 
-fn prn(a: Vec<MalVal>) -> MalRet {
-    println!("{}", printer::pr_list(&a, true, "", "", " "));
+fn prn(caps: &Capabilities, a: Vec<MalVal>) -> MalRet {
+    caps.write_line("prn", &printer::pr_list(&a, true, "", "", " "))?;
     Ok(_nil())
 }
 
-fn println(a: Vec<MalVal>) -> MalRet {
-    println!("{}", printer::pr_list(&a, false, "", "", " "));
+fn println(caps: &Capabilities, a: Vec<MalVal>) -> MalRet {
+    caps.write_line("println", &printer::pr_list(&a, false, "", "", " "))?;
     Ok(_nil())
 }
 
-fn readline(a: Vec<MalVal>) -> MalRet {
+fn readline(caps: &Capabilities, a: Vec<MalVal>) -> MalRet {
+    if a.len() != 1 {
+        return err_str("Wrong arity to readline call");
+    }
+    caps.check_input("readline")?;
     match *a[0] {
-        Strn(ref a0) => match readline::mal_readline(&a0) {
+        Strn(ref a0) => match readline::mal_readline(a0) {
             Some(line) => Ok(string(line)),
             None       => err_val(_nil()),
         },
@@ -61,66 +119,209 @@ fn readline(a: Vec<MalVal>) -> MalRet {
     }
 }
 
+// With a second (file name) argument, reads the whole string as the
+// contents of that file and returns it as a single (do ...) form
 fn read_string(a: Vec<MalVal>) -> MalRet {
+    if a.is_empty() || a.len() > 2 {
+        return err_str("Wrong arity to read-string call");
+    }
+    if a.len() == 2 {
+        return match (&*a[0], &*a[1]) {
+            (Strn(a0), Strn(file)) => {
+                reader::read_file_str(a0.to_string(), file)
+            },
+            _ => err_str("read_string called with non-string"),
+        };
+    }
     match *a[0] {
         Strn(ref a0) => reader::read_str(a0.to_string()),
         _ => err_str("read_string called with non-string"),
     }
 }
 
-fn slurp(a: Vec<MalVal>) -> MalRet {
+fn slurp(caps: &Capabilities, a: Vec<MalVal>) -> MalRet {
+    if a.len() != 1 {
+        return err_str("Wrong arity to slurp call");
+    }
     match *a[0] {
-        Strn(ref a0) => {
-            let mut s = String::new();
-            match File::open(a0).and_then(|mut f| f.read_to_string(&mut s)) {
-                Ok(_) => Ok(string(s)),
-                Err(e) => err_string(e.to_string()),
-            }
-        },
+        Strn(ref a0) => caps.read_to_string("slurp", a0).map(string),
         _ => err_str("slurp called with non-string"),
     }
 }
 
 
 // Numeric functions
-fn int_op<F>(f: F, a: Vec<MalVal>) -> MalRet
-    where F: FnOnce(isize, isize) -> isize
+
+// Operands are promoted to a common representation before applying an
+// operation: Int < BigInt < Ratio < Float
+enum Num {
+    I(isize),
+    B(BigInt),
+    R(BigRational),
+    F(f64),
+}
+
+impl Num {
+    fn rank(&self) -> u8 {
+        match *self { Num::I(_) => 0, Num::B(_) => 1, Num::R(_) => 2, Num::F(_) => 3 }
+    }
+
+    fn into_rank(self, rank: u8) -> Num {
+        match (self, rank) {
+            (Num::I(i), 1) => Num::B(BigInt::from(i)),
+            (Num::I(i), 2) => Num::R(BigRational::from_integer(BigInt::from(i))),
+            (Num::I(i), 3) => Num::F(i as f64),
+            (Num::B(b), 2) => Num::R(BigRational::from_integer(b)),
+            (Num::B(b), 3) => Num::F(b.to_f64().unwrap_or(f64::NAN)),
+            (Num::R(r), 3) => Num::F(r.to_f64().unwrap_or(f64::NAN)),
+            (n, _) => n,
+        }
+    }
+
+    fn is_zero(&self) -> bool {
+        match *self {
+            Num::I(i) => i == 0,
+            Num::B(ref b) => b.is_zero(),
+            Num::R(ref r) => r.is_zero(),
+            Num::F(f) => f == 0.0,
+        }
+    }
+}
+
+fn to_num(mv: &MalVal) -> Result<Num,MalError> {
+    match **mv {
+        Int(i) => Ok(Num::I(i)),
+        MalType::BigInt(ref b) => Ok(Num::B(b.clone())),
+        MalType::Ratio(ref r) => Ok(Num::R(r.clone())),
+        MalType::Float(f) => Ok(Num::F(f)),
+        _ => Err(MalError::runtime(format!("{} is not a number", mv.pr_str(true)))),
+    }
+}
+
+fn from_num(n: Num) -> MalVal {
+    match n {
+        Num::I(i) => _int(i),
+        Num::B(b) => types::_bigint(b),
+        Num::R(r) => types::_ratio(r),
+        Num::F(f) => types::_float(f),
+    }
+}
+
+fn promote(a: &MalVal, b: &MalVal) -> Result<(Num,Num),MalError> {
+    let (a, b) = (to_num(a)?, to_num(b)?);
+    let rank = cmp::max(a.rank(), b.rank());
+    Ok((a.into_rank(rank), b.into_rank(rank)))
+}
+
+fn num_op(a: &MalVal, b: &MalVal,
+          iop: fn(isize, isize) -> Option<isize>,
+          bop: fn(BigInt, BigInt) -> BigInt,
+          rop: fn(BigRational, BigRational) -> BigRational,
+          fop: fn(f64, f64) -> f64) -> MalRet
 {
-    match *a[0] {
-        Int(a0) => match *a[1] {
-            Int(a1) => Ok(_int(f(a0,a1))),
-            _ => err_str("second arg must be an int"),
+    match promote(a, b)? {
+        (Num::I(x), Num::I(y)) => match iop(x, y) {
+            Some(i) => Ok(_int(i)),
+            None => err_str("integer overflow"),
         },
-        _ => err_str("first arg must be an int"),
+        (Num::B(x), Num::B(y)) => Ok(from_num(Num::B(bop(x, y)))),
+        (Num::R(x), Num::R(y)) => Ok(from_num(Num::R(rop(x, y)))),
+        (Num::F(x), Num::F(y)) => Ok(from_num(Num::F(fop(x, y)))),
+        _ => unreachable!(),
     }
 }
 
-fn bool_op<F>(f: F, a: Vec<MalVal>) -> MalRet
-    where F: FnOnce(isize, isize) -> bool
-{
-    match *a[0] {
-        Int(a0) => match *a[1] {
-            Int(a1) => {
-                match f(a0,a1) {
-                    true => Ok(_true()),
-                    false => Ok(_false()),
-                }
-            },
-            _ => err_str("second arg must be an int"),
+fn add2(a: &MalVal, b: &MalVal) -> MalRet {
+    num_op(a, b, isize::checked_add, |i,j| { i+j }, |i,j| { i+j }, |i,j| { i+j })
+}
+
+fn sub2(a: &MalVal, b: &MalVal) -> MalRet {
+    num_op(a, b, isize::checked_sub, |i,j| { i-j }, |i,j| { i-j }, |i,j| { i-j })
+}
+
+fn mul2(a: &MalVal, b: &MalVal) -> MalRet {
+    num_op(a, b, isize::checked_mul, |i,j| { i*j }, |i,j| { i*j }, |i,j| { i*j })
+}
+
+// Division after promotion: when both operands are integers (Int or
+// BigInt) the quotient is truncated towards zero, as the upstream tests
+// require, so (/ 1 3) is 0 and (/ 4) is 0. Only when either operand is
+// a ratio is the division exact, giving a ratio, and when either is a
+// float it is float division. Dividing an exact number by zero is an
+// error; dividing a float by zero gives an infinity or NaN.
+fn div2(a: &MalVal, b: &MalVal) -> MalRet {
+    let (x, y) = promote(a, b)?;
+    match (x, y) {
+        (Num::F(x), Num::F(y)) => Ok(from_num(Num::F(x / y))),
+        (_, ref y) if y.is_zero() => err_str("Divide by zero"),
+        (Num::I(x), Num::I(y)) => match x.checked_div(y) {
+            Some(i) => Ok(_int(i)),
+            None => err_str("integer overflow"),
         },
-        _ => err_str("first arg must be an int"),
+        (Num::B(x), Num::B(y)) => Ok(from_num(Num::B(x / y))),
+        (Num::R(x), Num::R(y)) => Ok(from_num(Num::R(x / y))),
+        _ => unreachable!(),
+    }
+}
+
+fn fold_op(init: MalVal, a: &[MalVal],
+           f: fn(&MalVal, &MalVal) -> MalRet) -> MalRet {
+    let mut acc = init;
+    for mv in a.iter() {
+        acc = f(&acc, mv)?;
     }
+    Ok(acc)
 }
 
-pub fn add(a: Vec<MalVal>) -> MalRet { int_op(|i,j| { i+j }, a) }
-pub fn sub(a: Vec<MalVal>) -> MalRet { int_op(|i,j| { i-j }, a) }
-pub fn mul(a: Vec<MalVal>) -> MalRet { int_op(|i,j| { i*j }, a) }
-pub fn div(a: Vec<MalVal>) -> MalRet { int_op(|i,j| { i/j }, a) }
+fn cmp2(a: &MalVal, b: &MalVal) -> Result<Option<Ordering>,MalError> {
+    Ok(match promote(a, b)? {
+        (Num::I(x), Num::I(y)) => x.partial_cmp(&y),
+        (Num::B(x), Num::B(y)) => x.partial_cmp(&y),
+        (Num::R(x), Num::R(y)) => x.partial_cmp(&y),
+        (Num::F(x), Num::F(y)) => x.partial_cmp(&y),
+        _ => unreachable!(),
+    })
+}
 
-pub fn lt (a: Vec<MalVal>) -> MalRet { bool_op(|i,j| { i<j }, a) }
-pub fn lte(a: Vec<MalVal>) -> MalRet { bool_op(|i,j| { i<=j }, a) }
-pub fn gt (a: Vec<MalVal>) -> MalRet { bool_op(|i,j| { i>j }, a) }
-pub fn gte(a: Vec<MalVal>) -> MalRet { bool_op(|i,j| { i>=j }, a) }
+// True when every adjacent pair of arguments satisfies f
+fn cmp_op(name: &str, a: Vec<MalVal>, f: fn(Ordering) -> bool) -> MalRet {
+    if a.is_empty() {
+        return err_string(format!("Wrong arity to {} call", name));
+    }
+    to_num(&a[0])?;
+    for w in a.windows(2) {
+        // comparisons involving NaN are always false
+        match cmp2(&w[0], &w[1])?.map(f) {
+            Some(true) => (),
+            _ => return Ok(_false()),
+        }
+    }
+    Ok(_true())
+}
+
+// Clojure style variadic arithmetic: (+) is 0, (*) is 1, (- x) negates
+// and (/ x) is the reciprocal of x
+pub fn add(a: Vec<MalVal>) -> MalRet { fold_op(_int(0), &a, add2) }
+pub fn mul(a: Vec<MalVal>) -> MalRet { fold_op(_int(1), &a, mul2) }
+pub fn sub(a: Vec<MalVal>) -> MalRet {
+    match a.len() {
+        0 => err_str("Wrong arity to - call"),
+        1 => sub2(&_int(0), &a[0]),
+        _ => fold_op(a[0].clone(), &a[1..], sub2),
+    }
+}
+pub fn div(a: Vec<MalVal>) -> MalRet {
+    match a.len() {
+        0 => err_str("Wrong arity to / call"),
+        1 => div2(&_int(1), &a[0]),
+        _ => fold_op(a[0].clone(), &a[1..], div2),
+    }
+}
+
+pub fn lt (a: Vec<MalVal>) -> MalRet { cmp_op("<", a, |o| { o == Ordering::Less }) }
+pub fn lte(a: Vec<MalVal>) -> MalRet { cmp_op("<=", a, |o| { o != Ordering::Greater }) }
+pub fn gt (a: Vec<MalVal>) -> MalRet { cmp_op(">", a, |o| { o == Ordering::Greater }) }
+pub fn gte(a: Vec<MalVal>) -> MalRet { cmp_op(">=", a, |o| { o != Ordering::Less }) }
 
 pub fn time_ms(_a: Vec<MalVal>) -> MalRet {
     //let x = time::now();
@@ -163,14 +364,9 @@ pub fn get(a: Vec<MalVal>) -> MalRet {
         Nil => return Ok(_nil()),
         _ => return err_str("get on non-hash map"),
     };
-    match *a[1] {
-        Strn(ref key) => {
-            match hm.get(key) {
-                Some(v) => Ok(v.clone()),
-                None    => Ok(_nil()),
-            }
-        },
-        _ => err_str("get with non-string key"),
+    match hm.get(&a[1]) {
+        Some(v) => Ok(v.clone()),
+        None    => Ok(_nil()),
     }
 }
 
@@ -183,14 +379,9 @@ pub fn contains_q(a: Vec<MalVal>) -> MalRet {
         Nil => return Ok(_false()),
         _ => return err_str("contains? on non-hash map"),
     };
-    match *a[1] {
-        Strn(ref key) => {
-            match hm.contains_key(key) {
-                true  => Ok(_true()),
-                false => Ok(_false()),
-            }
-        }
-        _ => err_str("contains? with non-string key"),
+    match hm.contains_key(&a[1]) {
+        true  => Ok(_true()),
+        false => Ok(_false()),
     }
 }
 
@@ -203,27 +394,30 @@ pub fn keys(a: Vec<MalVal>) -> MalRet {
         Nil => return Ok(_nil()),
         _ => return err_str("contains? on non-hash map"),
     };
-    Ok(list(hm.keys().map(|s| string(s.to_string())).collect()))
+    Ok(list(hm.keys().cloned().collect::<MalVec>()))
 }
 
 pub fn vals(a: Vec<MalVal>) -> MalRet {
     if a.len() != 1 {
-        return err_str("Wrong arity to values call");
+        return err_str("Wrong arity to vals call");
     }
     let hm = match *a[0] {
         Hash_Map(ref hm,_) => hm,
         Nil => return Ok(_nil()),
         _ => return err_str("contains? on non-hash map"),
     };
-    Ok(list(hm.values().map(|s| s.clone()).collect()))
+    Ok(list(hm.values().cloned().collect::<MalVec>()))
 }
 
 // Sequence functions
 pub fn cons(a: Vec<MalVal>) -> MalRet {
+    if a.len() != 2 {
+        return err_str("Wrong arity to cons call");
+    }
     match *a[1] {
         List(ref v,_) | Vector(ref v,_) => {
             let mut new_v = v.clone();
-            new_v.insert(0, a[0].clone());
+            new_v.push_front(a[0].clone());
             Ok(list(new_v))
         },
         _ => err_str("Second arg to cons not a sequence"),
@@ -231,10 +425,10 @@ pub fn cons(a: Vec<MalVal>) -> MalRet {
 }
 
 pub fn concat(a: Vec<MalVal>) -> MalRet {
-    let mut new_v: Vec<MalVal> = vec![];
+    let mut new_v = MalVec::new();
     for lst in a.iter() {
         match **lst {
-            List(ref l,_) | Vector(ref l,_) => new_v.extend(l.clone()),
+            List(ref l,_) | Vector(ref l,_) => new_v.append(l.clone()),
             _ => return err_str("concat called with non-sequence"),
         }
     }
@@ -274,7 +468,7 @@ pub fn first(a: Vec<MalVal>) -> MalRet {
         Nil => return Ok(_nil()),
         _ => return err_str("first called with non-sequence"),
     };
-    if seq.len() == 0 {
+    if seq.is_empty() {
         Ok(_nil())
     } else {
         Ok(seq[0].clone())
@@ -290,10 +484,10 @@ pub fn rest(a: Vec<MalVal>) -> MalRet {
         Nil => return Ok(list(vec![])),
         _ => return err_str("rest called with non-sequence"),
     };
-    if seq.len() == 0 {
+    if seq.is_empty() {
         Ok(list(vec![]))
     } else {
-        Ok(list(seq[1..].to_vec()))
+        Ok(list(seq.skip(1)))
     }
 }
 
@@ -327,11 +521,11 @@ pub fn apply(a: Vec<MalVal>) -> MalRet {
     if a.len() < 2 {
         return err_str("apply call needs 2 or more arguments");
     }
-    let ref f = a[0];
+    let f = &a[0];
     let mut args = a[1..a.len()-1].to_vec();
     match *a[a.len()-1] {
         List(ref v, _) | Vector(ref v, _) => {
-            args.extend(v.clone());
+            args.extend(v.iter().cloned());
             f.apply(args)
         },
         _ => err_str("apply call with non-sequence"),
@@ -346,7 +540,7 @@ pub fn map(a: Vec<MalVal>) -> MalRet {
     match *a[1] {
         List(ref v,_) | Vector(ref v,_) => {
             for mv in v.iter() {
-                let res = try!(a[0].apply(vec![mv.clone()]));
+                let res = a[0].apply(vec![mv.clone()])?;
                 results.push(res);
             }
         },
@@ -359,19 +553,18 @@ pub fn conj(a: Vec<MalVal>) -> MalRet {
     if a.len() < 2 {
         return err_str("Wrong arity to conj call");
     }
-    let mut new_v: Vec<MalVal> = vec![];
     match *a[0] {
         List(ref l,_) => {
-            new_v.extend(l.clone());
+            let mut new_v = l.clone();
             for mv in a.iter().skip(1) {
-                new_v.insert(0, mv.clone());
+                new_v.push_front(mv.clone());
             }
             Ok(list(new_v))
         }
         Vector(ref l,_) => {
-            new_v.extend(l.clone());
+            let mut new_v = l.clone();
             for mv in a.iter().skip(1) {
-                new_v.push(mv.clone());
+                new_v.push_back(mv.clone());
             }
             Ok(vector(new_v))
         }
@@ -387,18 +580,15 @@ pub fn seq(a: Vec<MalVal>) -> MalRet {
     match *a[0] {
         List(ref l,_) |
         Vector(ref l,_) => {
-            if l.len() == 0 {
+            if l.is_empty() {
                 Ok(_nil())
             } else {
-                new_v.extend(l.clone());
-                Ok(list(new_v))
+                Ok(list(l.clone()))
             }
         },
         Strn(ref s) => {
-            if s.len() == 0 {
+            if s.is_empty() {
                 Ok(_nil())
-            } else if s.starts_with("\u{29e}") {
-                err_str("seq: called with non-sequence")
             } else {
                 for c in s.chars() {
                     new_v.push(string(c.to_string()));
@@ -424,7 +614,7 @@ fn with_meta(a: Vec<MalVal>) -> MalRet {
         Vector(ref v,_) => Ok(vectorm(v.clone(), meta)),
         Hash_Map(ref hm,_) => Ok(hash_mapm(hm.clone(), meta)),
         MalFunc(ref mfd,_) => Ok(malfuncd(mfd.clone(), meta)),
-        Func(f,_) => Ok(funcm(f, meta)),
+        Func(ref fd,_) => Ok(funcd(fd.clone(), meta)),
         _ => err_str("type does not support metadata"),
     }
 }
@@ -445,23 +635,28 @@ fn meta(a: Vec<MalVal>) -> MalRet {
 
 // Atom functions
 fn deref(a: Vec<MalVal>) -> MalRet {
-    if a.len() != 1 {
+    if a.is_empty() {
         return err_str("Wrong arity to deref call");
     }
     match *a[0] {
+        Atom(_) if a.len() != 1 => err_str("Wrong arity to deref call"),
         Atom(ref val) => Ok(val.borrow().clone()),
+        #[cfg(feature = "threads")]
+        MalType::Promise(ref p) => threads::deref(p, &a),
         _ => err_str("deref called on non-atom"),
     }
 }
 
 fn reset_bang(a: Vec<MalVal>) -> MalRet {
     if a.len() != 2 {
-        return err_str("Wrong arity to map call");
+        return err_str("Wrong arity to reset! call");
     }
     match *a[0] {
         Atom(ref val) => {
             let mut val_cell = val.borrow_mut();
             *val_cell = a[1].clone();
+            #[cfg(feature = "threads")]
+            swapping::wrote(val);
             Ok(a[1].clone())
         },
         _ => err_str("reset! called on non-atom"),
@@ -470,88 +665,212 @@ fn reset_bang(a: Vec<MalVal>) -> MalRet {
 
 fn swap_bang(a: Vec<MalVal>) -> MalRet {
     if a.len() < 2 {
-        return err_str("Wrong arity to swap_q call");
+        return err_str("Wrong arity to swap! call");
     }
-    let f = a[1].clone();
     match *a[0] {
-        Atom(ref val) => {
-            let mut val_cell = val.borrow_mut();
-            let mut args = a[2..].to_vec();
-            args.insert(0, val_cell.clone());
-            *val_cell = try!(f.apply(args));
-            Ok(val_cell.clone())
-        },
+        Atom(ref val) => swap_atom(val, &a[1], &a[2..]),
         _ => err_str("swap! called on non-atom"),
     }
 }
 
+// f is called without the atom borrowed, so it may use the atom itself;
+// what it returns replaces whatever the atom then holds
+#[cfg(not(feature = "threads"))]
+fn swap_atom(val: &RefCell<MalVal>, f: &MalVal, rest: &[MalVal]) -> MalRet {
+    let mut args = vec![val.borrow().clone()];
+    args.extend_from_slice(rest);
+    let new = f.apply(args)?;
+    *val.borrow_mut() = new.clone();
+    Ok(new)
+}
+
+// As without threads, but f is called again should another thread have
+// changed the atom in the meantime, up to SWAP_TRIES times in all.
+// Changes f makes to the atom itself don't count, as calling f again
+// would only make them again.
+#[cfg(feature = "threads")]
+fn swap_atom(val: &RefCell<MalVal>, f: &MalVal, rest: &[MalVal]) -> MalRet {
+    for _ in 0..SWAP_TRIES {
+        let old = val.borrow().clone();
+        let mut args = vec![old.clone()];
+        args.extend_from_slice(rest);
+        let (new, wrote) = swapping::apply(val, f, args)?;
+        let mut val_cell = val.borrow_mut();
+        if wrote || Rc::ptr_eq(&val_cell, &old) {
+            *val_cell = new.clone();
+            swapping::wrote(val);
+            return Ok(new);
+        }
+    }
+    err_string(format!("swap! gave up after {} tries, the atom being changed by other threads \
+                        each time", SWAP_TRIES))
+}
+
+#[cfg(feature = "threads")]
+const SWAP_TRIES: usize = 1000;
+
+// The atoms the current thread is calling swap! functions for, and
+// whether the thread has written to each since
+#[cfg(feature = "threads")]
+mod swapping {
+    use std::cell::RefCell as LocalCell;
+
+    use sync::RefCell;
+    use types::{MalVal, MalError};
+
+    thread_local! {
+        static ATOMS: LocalCell<Vec<(usize, bool)>> = const { LocalCell::new(vec![]) };
+    }
+
+    fn id(val: &RefCell<MalVal>) -> usize {
+        val as *const RefCell<MalVal> as usize
+    }
+
+    // Call f for a swap! of the atom holding val, and tell whether it
+    // wrote to the atom
+    pub fn apply(val: &RefCell<MalVal>, f: &MalVal, args: Vec<MalVal>)
+                 -> Result<(MalVal, bool), MalError> {
+        ATOMS.with(|a| a.borrow_mut().push((id(val), false)));
+        let _entry = Entry;
+        let res = f.apply(args);
+        let wrote = ATOMS.with(|a| a.borrow().last().is_some_and(|e| e.1));
+        res.map(|new| (new, wrote))
+    }
+
+    // Pops the atom apply pushed, even should f panic
+    struct Entry;
+
+    impl Drop for Entry {
+        fn drop(&mut self) {
+            ATOMS.with(|a| a.borrow_mut().pop());
+        }
+    }
+
+    pub fn wrote(val: &RefCell<MalVal>) {
+        let id = id(val);
+        ATOMS.with(|a| for entry in a.borrow_mut().iter_mut() {
+            if entry.0 == id {
+                entry.1 = true;
+            }
+        });
+    }
+}
+
+// Memory functions
+fn collect(a: Vec<MalVal>) -> MalRet {
+    if !a.is_empty() {
+        return err_str("Wrong arity to gc call");
+    }
+    Ok(_int(gc::collect() as isize))
+}
+
+fn memory_stats(a: Vec<MalVal>) -> MalRet {
+    if !a.is_empty() {
+        return err_str("Wrong arity to memory-stats call");
+    }
+    let stats = gc::stats();
+    let mut hm = MalMap::new();
+    hm.insert(keyword("envs"), _int(stats.envs as isize));
+    hm.insert(keyword("atoms"), _int(stats.atoms as isize));
+    hm.insert(keyword("collections"), _int(stats.collections as isize));
+    hm.insert(keyword("freed"), _int(stats.freed as isize));
+    hm.insert(keyword("symbols"), _int(stats.symbols as isize));
+    Ok(hash_mapm(hm, _nil()))
+}
 
 pub fn ns() -> HashMap<String,MalVal> {
-    let mut ns = HashMap::new();;
-
-    ns.insert("=".to_string(), func(equal_q));
-    ns.insert("throw".to_string(), func(throw));
-    ns.insert("nil?".to_string(), func(types::nil_q));
-    ns.insert("true?".to_string(), func(types::true_q));
-    ns.insert("false?".to_string(), func(types::false_q));
-    ns.insert("string?".to_string(), func(types::string_q));
-    ns.insert("symbol".to_string(), func(types::_symbol));
-    ns.insert("symbol?".to_string(), func(types::symbol_q));
-    ns.insert("keyword".to_string(), func(types::_keyword));
-    ns.insert("keyword?".to_string(), func(types::keyword_q));
-
-    ns.insert("pr-str".to_string(), func(pr_str));
-    ns.insert("str".to_string(), func(str));
-    ns.insert("prn".to_string(), func(prn));
-    ns.insert("println".to_string(), func(println));
-    ns.insert("readline".to_string(), func(readline));
-    ns.insert("read-string".to_string(), func(read_string));
-    ns.insert("slurp".to_string(), func(slurp));
-
-    ns.insert("<".to_string(),  func(lt));
-    ns.insert("<=".to_string(), func(lte));
-    ns.insert(">".to_string(),  func(gt));
-    ns.insert(">=".to_string(), func(gte));
-    ns.insert("+".to_string(), func(add));
-    ns.insert("-".to_string(), func(sub));
-    ns.insert("*".to_string(), func(mul));
-    ns.insert("/".to_string(), func(div));
-    ns.insert("time-ms".to_string(), func(time_ms));
-
-    ns.insert("list".to_string(), func(types::listv));
-    ns.insert("list?".to_string(), func(types::list_q));
-    ns.insert("vector".to_string(), func(types::vectorv));
-    ns.insert("vector?".to_string(), func(types::vector_q));
-    ns.insert("hash-map".to_string(), func(types::hash_mapv));
-    ns.insert("map?".to_string(), func(types::hash_map_q));
-    ns.insert("assoc".to_string(), func(assoc));
-    ns.insert("dissoc".to_string(), func(dissoc));
-    ns.insert("get".to_string(), func(get));
-    ns.insert("contains?".to_string(), func(contains_q));
-    ns.insert("keys".to_string(), func(keys));
-    ns.insert("vals".to_string(), func(vals));
-
-    ns.insert("sequential?".to_string(), func(types::sequential_q));
-    ns.insert("cons".to_string(), func(cons));
-    ns.insert("concat".to_string(), func(concat));
-    ns.insert("empty?".to_string(), func(empty_q));
-    ns.insert("nth".to_string(), func(nth));
-    ns.insert("first".to_string(), func(first));
-    ns.insert("rest".to_string(), func(rest));
-    ns.insert("count".to_string(), func(count));
-    ns.insert("apply".to_string(), func(apply));
-    ns.insert("map".to_string(), func(map));
-
-    ns.insert("conj".to_string(), func(conj));
-    ns.insert("seq".to_string(), func(seq));
-
-    ns.insert("with-meta".to_string(), func(with_meta));
-    ns.insert("meta".to_string(), func(meta));
-    ns.insert("atom".to_string(), func(types::atom));
-    ns.insert("atom?".to_string(), func(types::atom_q));
-    ns.insert("deref".to_string(), func(deref));
-    ns.insert("reset!".to_string(), func(reset_bang));
-    ns.insert("swap!".to_string(), func(swap_bang));
-
-    return ns;
+    ns_with(&Capabilities::all())
+}
+
+// The I/O built-ins only do what caps allows
+pub fn ns_with(caps: &Capabilities) -> HashMap<String,MalVal> {
+    let mut ns = HashMap::new();
+    let caps = Rc::new(caps.clone());
+
+    ns.insert("=".to_string(), native_fn("=", equal_q));
+    ns.insert("throw".to_string(), native_fn("throw", throw));
+    ns.insert("ex-info".to_string(), native_fn("ex-info", ex_info));
+    ns.insert("ex-message".to_string(), native_fn("ex-message", ex_message));
+    ns.insert("ex-data".to_string(), native_fn("ex-data", ex_data));
+    ns.insert("ex-cause".to_string(), native_fn("ex-cause", ex_cause));
+    ns.insert("nil?".to_string(), native_fn("nil?", types::nil_q));
+    ns.insert("true?".to_string(), native_fn("true?", types::true_q));
+    ns.insert("false?".to_string(), native_fn("false?", types::false_q));
+    ns.insert("string?".to_string(), native_fn("string?", types::string_q));
+    ns.insert("symbol".to_string(), native_fn("symbol", types::_symbol));
+    ns.insert("symbol?".to_string(), native_fn("symbol?", types::symbol_q));
+    ns.insert("keyword".to_string(), native_fn("keyword", types::_keyword));
+    ns.insert("keyword?".to_string(), native_fn("keyword?", types::keyword_q));
+
+    ns.insert("pr-str".to_string(), native_fn("pr-str", pr_str));
+    ns.insert("str".to_string(), native_fn("str", str));
+    let c = caps.clone();
+    ns.insert("prn".to_string(), native_fn("prn", move |a| prn(&c, a)));
+    let c = caps.clone();
+    ns.insert("println".to_string(), native_fn("println", move |a| println(&c, a)));
+    let c = caps.clone();
+    ns.insert("readline".to_string(), native_fn("readline", move |a| readline(&c, a)));
+    ns.insert("read-string".to_string(), native_fn("read-string", read_string));
+    let c = caps.clone();
+    ns.insert("slurp".to_string(), native_fn("slurp", move |a| slurp(&c, a)));
+
+    ns.insert("<".to_string(), native_fn("<", lt));
+    ns.insert("<=".to_string(), native_fn("<=", lte));
+    ns.insert(">".to_string(), native_fn(">", gt));
+    ns.insert(">=".to_string(), native_fn(">=", gte));
+    ns.insert("+".to_string(), native_fn("+", add));
+    ns.insert("-".to_string(), native_fn("-", sub));
+    ns.insert("*".to_string(), native_fn("*", mul));
+    ns.insert("/".to_string(), native_fn("/", div));
+    ns.insert("time-ms".to_string(), native_fn("time-ms", time_ms));
+
+    ns.insert("list".to_string(), native_fn("list", types::listv));
+    ns.insert("list?".to_string(), native_fn("list?", types::list_q));
+    ns.insert("vector".to_string(), native_fn("vector", types::vectorv));
+    ns.insert("vector?".to_string(), native_fn("vector?", types::vector_q));
+    ns.insert("hash-map".to_string(), native_fn("hash-map", types::hash_mapv));
+    ns.insert("map?".to_string(), native_fn("map?", types::hash_map_q));
+    ns.insert("assoc".to_string(), native_fn("assoc", assoc));
+    ns.insert("dissoc".to_string(), native_fn("dissoc", dissoc));
+    ns.insert("get".to_string(), native_fn("get", get));
+    ns.insert("contains?".to_string(), native_fn("contains?", contains_q));
+    ns.insert("keys".to_string(), native_fn("keys", keys));
+    ns.insert("vals".to_string(), native_fn("vals", vals));
+
+    ns.insert("sequential?".to_string(), native_fn("sequential?", types::sequential_q));
+    ns.insert("cons".to_string(), native_fn("cons", cons));
+    ns.insert("concat".to_string(), native_fn("concat", concat));
+    ns.insert("empty?".to_string(), native_fn("empty?", empty_q));
+    ns.insert("nth".to_string(), native_fn("nth", nth));
+    ns.insert("first".to_string(), native_fn("first", first));
+    ns.insert("rest".to_string(), native_fn("rest", rest));
+    ns.insert("count".to_string(), native_fn("count", count));
+    ns.insert("apply".to_string(), native_fn("apply", apply));
+    ns.insert("map".to_string(), native_fn("map", map));
+
+    ns.insert("conj".to_string(), native_fn("conj", conj));
+    ns.insert("seq".to_string(), native_fn("seq", seq));
+
+    ns.insert("with-meta".to_string(), native_fn("with-meta", with_meta));
+    ns.insert("meta".to_string(), native_fn("meta", meta));
+    ns.insert("atom".to_string(), native_fn("atom", types::atom));
+    ns.insert("atom?".to_string(), native_fn("atom?", types::atom_q));
+    ns.insert("deref".to_string(), native_fn("deref", deref));
+    ns.insert("reset!".to_string(), native_fn("reset!", reset_bang));
+    ns.insert("swap!".to_string(), native_fn("swap!", swap_bang));
+
+    ns.insert("gc".to_string(), native_fn("gc", collect));
+    ns.insert("memory-stats".to_string(), native_fn("memory-stats", memory_stats));
+
+    #[cfg(feature = "threads")]
+    {
+        ns.insert("future-call".to_string(), native_fn("future-call", threads::future_call));
+        ns.insert("future?".to_string(), native_fn("future?", threads::future_q));
+        ns.insert("promise".to_string(), native_fn("promise", threads::promise));
+        ns.insert("deliver".to_string(), native_fn("deliver", threads::deliver));
+        ns.insert("realized?".to_string(), native_fn("realized?", threads::realized_q));
+        ns.insert("pmap".to_string(), native_fn("pmap", threads::pmap));
+    }
+
+    ns
 }
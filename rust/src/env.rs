@@ -1,53 +1,107 @@
-use std::rc::Rc;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::mem;
+use std::cell::RefCell as LocalCell;
+use std::collections::HashSet;
+use std::hash::BuildHasherDefault;
 
-use types::{MalVal, MalRet, _nil, list, err_string};
-use types::MalType::{Sym, List, Vector};
+use sync::{Rc, RefCell};
+use namespace::NsTag;
+use types::{MalVal, MalRet, MalVec, MalMap, Symbol, SymMap, IdHasher,
+            _nil, list, keyword, strn, symbol, err_string};
+use types::MalType::{Nil, Sym, Keyword, List, Vector, Hash_Map};
 
-struct EnvType {
-    data: HashMap<String,MalVal>,
+pub struct EnvType {
+    // locals bound by fn* parameters, let*, loop* and catch*, in the
+    // order they were first bound, which the analyzer relies on
+    slots: Vec<(Symbol, MalVal)>,
+    data: SymMap<MalVal>,  // definitions made with def!
     outer: Option<Env>,
+    ns: Option<NsTag>,  // set on the top-level env of a namespace
+    dynamic: HashSet<Symbol, BuildHasherDefault<IdHasher>>,  // symbols that binding may rebind
 }
 
 pub type Env = Rc<RefCell<EnvType>>;
 
+thread_local! {
+    // The values binding has given dynamic vars on the current thread,
+    // innermost last: the env defining each var, its name and its value
+    static BINDINGS: LocalCell<Vec<(Env, Symbol, MalVal)>> = const { LocalCell::new(Vec::new()) };
+}
+
+fn new_env(outer: Option<Env>, ns: Option<NsTag>) -> Env {
+    Rc::new(RefCell::new(EnvType{slots: Vec::new(), data: SymMap::default(),
+                                 outer, ns,
+                                 dynamic: HashSet::default()}))
+}
+
 pub fn env_new(outer: Option<Env>) -> Env {
-    Rc::new(RefCell::new(EnvType{data: HashMap::new(), outer: outer}))
+    new_env(outer, None)
+}
+
+pub fn env_new_ns(outer: Option<Env>, tag: NsTag) -> Env {
+    new_env(outer, Some(tag))
+}
+
+// The namespace env belongs to, found from its nearest tagged env
+pub fn env_ns(env: &Env) -> Option<NsTag> {
+    let e = env.borrow();
+    match e.ns {
+        Some(ref tag) => Some(tag.clone()),
+        None => match e.outer {
+            Some(ref outer) => env_ns(outer),
+            None => None,
+        },
+    }
+}
+
+// Symbols defined directly in env, not in its outer envs
+pub fn env_symbols(env: &Env) -> Vec<String> {
+    env.borrow().data.keys().map(|k| k.to_string()).collect()
+}
+
+// Symbols bound as locals in env, in slot order
+pub fn env_local_names(env: &Env) -> Vec<Symbol> {
+    env.borrow().slots.iter().map(|(k, _)| k.clone()).collect()
+}
+
+// Number of fixed parameters in a parameter list, and whether it also
+// takes a rest parameter
+pub fn param_arity(mbinds: &MalVal) -> (usize, bool) {
+    let mut fixed = 0;
+    let mut variadic = false;
+    if let List(ref binds,_) | Vector(ref binds,_) = **mbinds {
+        let mut it = binds.iter();
+        while let Some(b) = it.next() {
+            match **b {
+                Sym(ref strn) if *strn == "&" => { variadic = true; it.next(); },
+                Keyword(ref k) if &**k == "as" => { it.next(); },
+                _ => fixed += 1,
+            }
+        }
+    }
+    (fixed, variadic)
+}
+
+// Expected argument count of a parameter list, for error messages
+pub fn arity_str(mbinds: &MalVal) -> String {
+    match param_arity(mbinds) {
+        (fixed, false) => fixed.to_string(),
+        (fixed, true) => format!("{} or more", fixed),
+    }
 }
 
 pub fn env_bind(env: &Env,
                 mbinds: MalVal,
                 mexprs: MalVal) -> Result<Env,String> {
-    let mut variadic = false;
     match *mbinds {
         List(ref binds,_) | Vector(ref binds,_) => {
             match *mexprs {
                 List(ref exprs,_) | Vector(ref exprs,_) => {
-                    let mut it = binds.iter().enumerate();
-                    for (i, b) in it.by_ref() {
-                        match **b {
-                            Sym(ref strn) => {
-                                if *strn == "&" {
-                                    variadic = true;
-                                    break;
-                                } else {
-                                    env_set(env, b.clone(), exprs[i].clone());
-                                }
-                            }
-                            _ => return Err("non-symbol bind".to_string()),
-                        }
-                    }
-                    if variadic {
-                        let (i, sym) = it.next().unwrap();
-                        match **sym {
-                            Sym(_) => {
-                                let rest = exprs[i-1..].to_vec();
-                                env_set(env, sym.clone(), list(rest));
-                            }
-                            _ => return Err("& bind to non-symbol".to_string()),
-                        }
+                    let (fixed, variadic) = param_arity(&mbinds);
+                    if exprs.len() < fixed || (!variadic && exprs.len() > fixed) {
+                        return Err(format!("Wrong number of args ({}), expected {}",
+                                           exprs.len(), arity_str(&mbinds)));
                     }
+                    bind_seq(env, binds, &mbinds, mexprs.clone())?;
                     Ok(env.clone())
                 },
                 _ => Err("exprs must be a list".to_string()),
@@ -57,15 +111,230 @@ pub fn env_bind(env: &Env,
     }
 }
 
+// Bind the arguments of a recur. Unlike env_bind, the argument count
+// must match exactly and a rest parameter takes a single seq as is.
+pub fn env_bind_recur(env: &Env,
+                      mbinds: MalVal,
+                      mexprs: MalVal) -> Result<Env,String> {
+    let binds = match *mbinds {
+        List(ref binds,_) | Vector(ref binds,_) => binds,
+        _ => return Err("binds must be a list".to_string()),
+    };
+    let exprs = match *mexprs {
+        List(ref exprs,_) | Vector(ref exprs,_) => exprs,
+        _ => return Err("exprs must be a list".to_string()),
+    };
+    let mut params = vec![];
+    let mut it = binds.iter();
+    while let Some(b) = it.next() {
+        match **b {
+            Sym(ref strn) if *strn == "&" => (),
+            Keyword(ref k) if &**k == "as" => { it.next(); },
+            _ => params.push(b),
+        }
+    }
+    if params.len() != exprs.len() {
+        return Err(format!("Mismatched argument count to recur, expected: {} args, got: {}",
+                           params.len(), exprs.len()));
+    }
+    for (b, e) in params.into_iter().zip(exprs.iter()) {
+        env_destructure(env, b, e.clone())?;
+    }
+    Ok(env.clone())
+}
+
+// Bind a binding form to a value: a symbol, a vector to destructure a
+// sequence, or a map to destructure a hash-map
+pub fn env_destructure(env: &Env, form: &MalVal, val: MalVal) -> Result<(),String> {
+    match **form {
+        Sym(_) => {
+            env_bind_local(env, form.clone(), val);
+            Ok(())
+        },
+        Vector(ref pats,_) => bind_seq(env, pats, form, val),
+        Hash_Map(ref pats,_) => bind_map(env, pats, form, val),
+        _ => Err(format!("unsupported binding form {}", form.pr_str(true))),
+    }
+}
+
+// [a b & more :as all]; missing elements are bound to nil
+fn is_as(mv: &MalVal) -> bool {
+    match **mv {
+        Keyword(ref k) => &**k == "as",
+        _ => false,
+    }
+}
+
+fn bind_seq(env: &Env, pats: &MalVec, form: &MalVal, val: MalVal) -> Result<(),String> {
+    let items = match *val {
+        List(ref items,_) | Vector(ref items,_) => items.clone(),
+        Nil => MalVec::new(),
+        _ => return Err(format!("cannot destructure {} with {}",
+                                val.pr_str(true), form.pr_str(true))),
+    };
+    let mut i = 0;
+    let mut it = pats.iter();
+    while let Some(p) = it.next() {
+        match **p {
+            Sym(ref strn) if *strn == "&" => {
+                let rest = match it.next() {
+                    Some(rest) => rest,
+                    None => return Err(format!("missing binding form after & in {}",
+                                               form.pr_str(true))),
+                };
+                let tail = if i < items.len() { items.skip(i) } else { MalVec::new() };
+                i = items.len();
+                env_destructure(env, rest, list(tail))?;
+                // only :as may follow the rest binding
+                match it.next() {
+                    Some(k) if is_as(k) => (),
+                    Some(extra) => return Err(format!("unexpected {} after & {} in {}",
+                                                      extra.pr_str(true), rest.pr_str(true),
+                                                      form.pr_str(true))),
+                    None => break,
+                }
+                let sym = match it.next() {
+                    Some(sym) => sym,
+                    None => return Err(format!("missing symbol after :as in {}",
+                                               form.pr_str(true))),
+                };
+                bind_as(env, sym, val.clone())?;
+                if let Some(extra) = it.next() {
+                    return Err(format!("unexpected {} after :as {} in {}",
+                                       extra.pr_str(true), sym.pr_str(true), form.pr_str(true)));
+                }
+            },
+            Keyword(ref k) if &**k == "as" => {
+                match it.next() {
+                    Some(sym) => bind_as(env, sym, val.clone())?,
+                    None => return Err(format!("missing symbol after :as in {}",
+                                               form.pr_str(true))),
+                }
+            },
+            _ => {
+                let item = match items.get(i) {
+                    Some(item) => item.clone(),
+                    None => _nil(),
+                };
+                i += 1;
+                env_destructure(env, p, item)?;
+            },
+        }
+    }
+    Ok(())
+}
+
+// {:keys [a b] :strs [c] :syms [d] :or {a 1} :as m, e :e}; :or
+// defaults are bound as written rather than evaluated
+fn bind_map(env: &Env, pats: &MalMap, form: &MalVal, val: MalVal) -> Result<(),String> {
+    let hm = match *val {
+        Hash_Map(ref hm,_) => hm.clone(),
+        Nil => MalMap::new(),
+        _ => return Err(format!("cannot destructure {} with {}",
+                                val.pr_str(true), form.pr_str(true))),
+    };
+    let defaults = match pats.get(&keyword("or")) {
+        Some(d) => match **d {
+            Hash_Map(ref d,_) => d.clone(),
+            _ => return Err(format!(":or must be a map in {}", form.pr_str(true))),
+        },
+        None => MalMap::new(),
+    };
+    let lookup = |key: &MalVal, pat: &MalVal| -> MalVal {
+        match hm.get(key).or_else(|| defaults.get(pat)) {
+            Some(v) => v.clone(),
+            None => _nil(),
+        }
+    };
+    for (k, v) in pats.iter() {
+        match **k {
+            Keyword(ref opt) => match &**opt {
+                "keys" | "strs" | "syms" => {
+                    let syms = match **v {
+                        List(ref syms,_) | Vector(ref syms,_) => syms,
+                        _ => return Err(format!(":{} must be a vector of symbols in {}",
+                                                opt, form.pr_str(true))),
+                    };
+                    for sym in syms.iter() {
+                        let name = match **sym {
+                            Sym(ref name) => name,
+                            _ => return Err(format!(":{} must be a vector of symbols in {}",
+                                                    opt, form.pr_str(true))),
+                        };
+                        let key = match &**opt {
+                            "keys" => keyword(name),
+                            "strs" => strn(name),
+                            _ => symbol(name),
+                        };
+                        env_bind_local(env, sym.clone(), lookup(&key, sym));
+                    }
+                },
+                "as" => bind_as(env, v, val.clone())?,
+                "or" => (),
+                _ => return Err(format!("unsupported option :{} in {}",
+                                        opt, form.pr_str(true))),
+            },
+            _ => env_destructure(env, k, lookup(v, k))?,
+        }
+    }
+    Ok(())
+}
+
+fn bind_as(env: &Env, sym: &MalVal, val: MalVal) -> Result<(),String> {
+    match **sym {
+        Sym(_) => {
+            env_bind_local(env, sym.clone(), val);
+            Ok(())
+        },
+        _ => Err(format!(":as must be followed by a symbol, got {}", sym.pr_str(true))),
+    }
+}
+
+impl EnvType {
+    fn get(&self, k: &Symbol) -> Option<MalVal> {
+        for (name, v) in self.slots.iter() {
+            if name == k {
+                return Some(v.clone());
+            }
+        }
+        if self.data.is_empty() {
+            None
+        } else {
+            self.data.get(k).cloned()
+        }
+    }
+}
+
+// The value of k in env or the nearest of its outer envs that has one
+fn lookup(env: &Env, k: &Symbol) -> Option<MalVal> {
+    let mut env = env.clone();
+    loop {
+        let outer = {
+            let e = env.borrow();
+            if let Some(v) = e.get(k) {
+                if !e.dynamic.is_empty() && e.dynamic.contains(k) {
+                    return Some(bound(&env, k).unwrap_or(v));
+                }
+                return Some(v);
+            }
+            match e.outer {
+                Some(ref outer) => outer.clone(),
+                None => return None,
+            }
+        };
+        env = outer;
+    }
+}
+
 pub fn env_find(env: &Env, key: &MalVal) -> Option<Env> {
     match **key {
         Sym(ref k) => {
-            let map = env.borrow();
-            if map.data.contains_key(k) {
+            let e = env.borrow();
+            if e.get(k).is_some() {
                 Some(env.clone())
             } else {
-                match map.outer {
-                    Some(ref e) => env_find(e, key),
+                match e.outer {
+                    Some(ref outer) => env_find(outer, key),
                     None => None,
                 }
             }
@@ -74,6 +343,46 @@ pub fn env_find(env: &Env, key: &MalVal) -> Option<Env> {
     }
 }
 
+pub fn env_outer(env: &Env) -> Option<Env> {
+    env.borrow().outer.clone()
+}
+
+// Something an env holds a reference to
+pub enum EnvRef<'a> {
+    Val(&'a MalVal),
+    Outer(&'a Env),
+}
+
+// Call f with each value bound in env and then with its outer env,
+// without holding any new references to them, for the cycle collector.
+// Returns false, having done nothing, if env is borrowed for changing.
+pub fn env_each_ref<F>(env: &Env, mut f: F) -> bool
+    where F: FnMut(EnvRef)
+{
+    let e = match env.try_borrow() {
+        Ok(e) => e,
+        Err(_) => return false,
+    };
+    for (_, v) in e.slots.iter() {
+        f(EnvRef::Val(v));
+    }
+    for v in e.data.values() {
+        f(EnvRef::Val(v));
+    }
+    if let Some(ref outer) = e.outer {
+        f(EnvRef::Outer(outer));
+    }
+    true
+}
+
+// Empty env, which nothing can reach any more, and hand back what it
+// held for the cycle collector to drop
+pub fn env_clear(env: &Env) -> EnvType {
+    let empty = EnvType{slots: Vec::new(), data: SymMap::default(), outer: None, ns: None,
+                        dynamic: HashSet::default()};
+    mem::replace(&mut *env.borrow_mut(), empty)
+}
+
 pub fn env_root(env: &Env) -> Env {
     match env.borrow().outer {
         Some(ref ei) => env_root(ei),
@@ -81,26 +390,144 @@ pub fn env_root(env: &Env) -> Env {
     }
 }
 
+// Define a symbol in env, as def! does
 pub fn env_set(env: &Env, key: MalVal, val: MalVal) {
-    match *key {
-        Sym(ref k) => { env.borrow_mut().data.insert(k.to_string(), val); }
-        _ => {},
+    if let Sym(ref k) = *key {
+        let mut e = env.borrow_mut();
+        // a def! of a name bound as a local rebinds the local
+        for slot in e.slots.iter_mut() {
+            if slot.0 == *k {
+                slot.1 = val;
+                return;
+            }
+        }
+        e.data.insert(k.clone(), val);
     }
 }
 
-pub fn env_get(env: &Env, key: &MalVal) -> MalRet {
+// Bind a local in env: a fn* parameter, or a let*, loop* or catch*
+// binding. Binding a symbol again reuses its slot.
+pub fn env_bind_local(env: &Env, key: MalVal, val: MalVal) {
+    if let Sym(ref k) = *key {
+        let mut e = env.borrow_mut();
+        for slot in e.slots.iter_mut() {
+            if slot.0 == *k {
+                slot.1 = val;
+                return;
+            }
+        }
+        e.slots.push((k.clone(), val));
+    }
+}
+
+// Mark whether a symbol defined in env is dynamic
+pub fn env_set_dynamic(env: &Env, key: &MalVal, dynamic: bool) {
+    if let Sym(ref k) = **key {
+        let mut e = env.borrow_mut();
+        if dynamic {
+            e.dynamic.insert(k.clone());
+        } else {
+            e.dynamic.remove(k);
+        }
+    }
+}
+
+pub fn env_is_dynamic(env: &Env, key: &MalVal) -> bool {
     match **key {
-        Sym(ref k) => {
-            match env_find(env, key) {
-                Some(e) => {
-                    match e.borrow().data.get(k) {
-                        Some(v) => Ok(v.clone()),
-                        None => Ok(_nil()),
-                    }
-                },
-                None => err_string(format!("'{}' not found", k)),
+        Sym(ref k) => env.borrow().dynamic.contains(k),
+        _ => false,
+    }
+}
+
+// The value the current thread has bound the dynamic var k of env to,
+// if it has
+fn bound(env: &Env, k: &Symbol) -> Option<MalVal> {
+    BINDINGS.with(|b| {
+        b.borrow().iter().rev()
+            .find(|binding| binding.1 == *k && Rc::ptr_eq(&binding.0, env))
+            .map(|binding| binding.2.clone())
+    })
+}
+
+/// Dynamic vars bound by `env_bind_dynamic`, until it is dropped.
+pub struct Bound(usize);
+
+// Give each (env, name, value) of vars the value on the current thread
+// only, until the Bound returned is dropped
+pub fn env_bind_dynamic(vars: Vec<(Env, MalVal, MalVal)>) -> Bound {
+    BINDINGS.with(|b| {
+        let mut b = b.borrow_mut();
+        let len = b.len();
+        for (env, name, val) in vars {
+            if let Sym(ref k) = *name {
+                b.push((env, k.clone(), val));
             }
         }
+        Bound(b.len() - len)
+    })
+}
+
+impl Drop for Bound {
+    fn drop(&mut self) {
+        let n = self.0;
+        BINDINGS.with(|b| {
+            let mut b = b.borrow_mut();
+            let len = b.len();
+            b.truncate(len - n);
+        });
+    }
+}
+
+// The current thread's dynamic bindings, put back when dropped
+struct Unbound(Vec<(Env, Symbol, MalVal)>);
+
+impl Drop for Unbound {
+    fn drop(&mut self) {
+        let saved = mem::take(&mut self.0);
+        BINDINGS.with(|b| *b.borrow_mut() = saved);
+    }
+}
+
+// Call f with none of the current thread's dynamic bindings in effect,
+// as for a future the thread runs on another's behalf. They are back in
+// effect afterwards, even if f panics.
+pub fn env_unbound<T, F: FnOnce() -> T>(f: F) -> T {
+    let _saved = Unbound(BINDINGS.with(|b| mem::take(&mut *b.borrow_mut())));
+    f()
+}
+
+pub fn env_get(env: &Env, key: &MalVal) -> MalRet {
+    match **key {
+        Sym(ref k) => match lookup(env, k) {
+            Some(v) => Ok(v),
+            None => err_string(format!("'{}' not found", k)),
+        },
         _ => err_string("env_get called with non-symbol key".to_string()),
     }
 }
+
+// The value of a local the analyzer resolved to slot index of the env
+// depth levels out. Should the env not have the expected shape after
+// all, the symbol is looked up by name instead.
+pub fn env_get_local(env: &Env, k: &Symbol, depth: usize, index: usize) -> MalRet {
+    {
+        let mut e = env.clone();
+        for _ in 0..depth {
+            let outer = match e.borrow().outer {
+                Some(ref outer) => outer.clone(),
+                None => break,
+            };
+            e = outer;
+        }
+        let e = e.borrow();
+        if let Some((name, v)) = e.slots.get(index) {
+            if name == k {
+                return Ok(v.clone());
+            }
+        }
+    }
+    match lookup(env, k) {
+        Some(v) => Ok(v),
+        None => err_string(format!("'{}' not found", k)),
+    }
+}
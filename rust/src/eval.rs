@@ -0,0 +1,814 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use types::{MalVal, MalRet, MalVec, MalMap, MalError, MalFuncData, ErrorKind, Frame, Pos,
+            err_str, err_string, form_pos};
+use types::{symbol, keyword, _nil, list, vector, hash_map, malfunc, malfuncd};
+use types::MalType::{Nil, False, Sym, Keyword, Local, List, Vector, Hash_Map, Func, MalFunc, Code,
+                      Expanded};
+use reader;
+use budget;
+use env::{env_set, env_get_local, env_bind_local, env_new, env_bind, env_bind_recur, env_destructure,
+          env_set_dynamic, env_is_dynamic, env_bind_dynamic, param_arity, Env};
+use namespace;
+use analyzer;
+use vm;
+
+// Evaluation nests on the native stack for every non-tail call, so the
+// nesting depth is limited to report deep recursion as a mal error
+// instead of overflowing the stack
+pub const DEFAULT_MAX_DEPTH: usize = 10000;
+
+/// How `eval` evaluates forms: by compiling them to bytecode for the
+/// VM (see `compiler` and `vm`), or by walking them as they are, which
+/// is kept as the reference for what the VM must do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Bytecode,
+    Tree,
+}
+
+thread_local! {
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+    static MAX_DEPTH: Cell<usize> = const { Cell::new(DEFAULT_MAX_DEPTH) };
+    static MODE: Cell<Mode> = const { Cell::new(Mode::Bytecode) };
+}
+
+// Shared by every thread, as fn bodies expanded on one may be called on
+// another (see threads)
+static MACROS: AtomicU64 = AtomicU64::new(0);
+
+pub fn mode() -> Mode {
+    MODE.with(|m| m.get())
+}
+
+pub fn set_mode(mode: Mode) {
+    MODE.with(|m| m.set(mode));
+}
+
+// Counts redefinitions of macros, so that expansions made before one
+// can be told apart and made again (see analyzer and compiler). Defining
+// a macro under a new name doesn't count: calls to it that were taken
+// for function calls are found to be macro calls when they are made.
+pub fn macro_generation() -> u64 {
+    MACROS.load(Ordering::Relaxed)
+}
+
+// Called before sym is rebound in env, in case it names a macro there
+fn redefine(env: &Env, sym: &MalVal) {
+    if let Ok(old) = namespace::resolve(env, sym) {
+        if let MalFunc(ref mfd,_) = *old {
+            if mfd.is_macro {
+                MACROS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+pub fn max_depth() -> usize {
+    MAX_DEPTH.with(|m| m.get())
+}
+
+pub fn set_max_depth(depth: usize) {
+    MAX_DEPTH.with(|m| m.set(depth));
+}
+
+// Called before entering the body of the named function, nested
+// one level deeper than the current evaluation
+pub fn check_depth(name: &str) -> Result<(), MalError> {
+    let max = max_depth();
+    if DEPTH.with(|d| d.get()) >= max {
+        let name = if name.is_empty() { "fn*" } else { name };
+        return Err(MalError::runtime(format!("stack overflow calling {}, max depth is {}",
+                                             name, max)));
+    }
+    Ok(())
+}
+
+// Counts levels of nesting for as long as it is alive, including
+// while unwinding from a panic
+pub struct DepthGuard(usize);
+
+// Count that many more levels of nesting until the guard is dropped,
+// for evaluation that nests outside eval_call
+pub fn nest(levels: usize) -> DepthGuard {
+    DEPTH.with(|d| d.set(d.get() + levels));
+    DepthGuard(levels)
+}
+
+impl Drop for DepthGuard {
+    fn drop(&mut self) {
+        let levels = self.0;
+        DEPTH.with(|d| d.set(d.get() - levels));
+    }
+}
+
+pub fn read(str: String) -> MalRet {
+    reader::read_str(str)
+}
+
+// eval
+fn is_pair(x: MalVal) -> bool {
+    match *x {
+        List(ref lst,_) | Vector(ref lst,_) => !lst.is_empty(),
+        _ => false,
+    }
+}
+
+// The form of an (unquote form) or (splice-unquote form)
+fn unquoted(name: &str, args: &MalVec) -> MalRet {
+    if args.len() != 2 {
+        return Err(MalError::runtime(format!("Wrong number of args ({}) passed to {}, expected 1",
+                                             args.len() - 1, name)));
+    }
+    Ok(args[1].clone())
+}
+
+pub fn quasiquote(ast: MalVal) -> MalRet {
+    if !is_pair(ast.clone()) {
+        return Ok(list(vec![symbol("quote"), ast]))
+    }
+
+    match *ast.clone() {
+        List(ref args,_) | Vector(ref args,_) => {
+            let a0 = &args[0];
+            match **a0 {
+                Sym(ref s) if *s == "unquote" => return unquoted(s, args),
+                _ => (),
+            }
+            if is_pair(a0.clone()) {
+                match **a0 {
+                    List(ref a0args,_) | Vector(ref a0args,_) => {
+                        match *a0args[0] {
+                            Sym(ref s) if *s == "splice-unquote" => {
+                                return Ok(list(vec![symbol("concat"),
+                                                    unquoted(s, a0args)?,
+                                                    quasiquote(list(args.skip(1)))?]))
+                            },
+                            _ => (),
+                        }
+                    },
+                    _ => (),
+                }
+            }
+            let rest = list(args.skip(1));
+            Ok(list(vec![symbol("cons"),
+                                quasiquote(a0.clone())?,
+                                quasiquote(rest)?]))
+        },
+        _ => Ok(_nil()), // should never reach
+    }
+}
+
+pub fn is_macro_call(ast: MalVal, env: Env) -> bool {
+    let lst = match *ast {
+        List(ref lst,_) => &lst[0],
+        _ => return false
+    };
+    match **lst {
+        Sym(_) => {},
+        _ => return false
+    }
+    let f = match namespace::resolve(&env, lst) {
+        Ok(f) => f,
+        _ => return false
+    };
+    match *f {
+        MalFunc(ref mfd,_) => mfd.is_macro,
+        _ => false,
+    }
+}
+
+pub fn macroexpand(mut ast: MalVal, env: Env) -> MalRet {
+    while is_macro_call(ast.clone(), env.clone()) {
+        let ast2 = ast.clone();
+        let args = match *ast2 {
+            List(ref args,_) => args,
+            _ => break,
+        };
+        let a0 = &args[0];
+        let mf = match **a0 {
+            Sym(_) => namespace::resolve(&env, a0)?,
+            _ => break,
+        };
+        match *mf {
+            MalFunc(_,_) => ast = mf.apply(args.iter().skip(1).map(analyzer::unanalyze).collect())?,
+            _ => break,
+        }
+    }
+    Ok(ast)
+}
+
+pub fn eval_ast(ast: MalVal, env: Env) -> MalRet {
+    match *ast {
+        Sym(_) => namespace::resolve(&env, &ast),
+        Local(ref sym, depth, index) => env_get_local(&env, sym, depth, index),
+        Code(_) => vm::eval(ast.clone(), env),
+        List(ref a,_) | Vector(ref a,_) => {
+            let mut ast_vec = MalVec::new();
+            for mv in a.iter() {
+                let mv2 = mv.clone();
+                ast_vec.push_back(walk(mv2, env.clone())?);
+            }
+            Ok(match *ast { List(_,_) => list(ast_vec),
+                            _         => vector(ast_vec) })
+        }
+        Hash_Map(ref hm,_) => {
+            let mut new_hm = MalMap::new();
+            for (key, value) in hm.iter() {
+                new_hm.insert(walk(key.clone(), env.clone())?,
+                              walk(value.clone(), env.clone())?);
+            }
+            Ok(hash_map(new_hm))
+        }
+        _ => Ok(ast.clone()),
+    }
+}
+
+// Give an anonymous function the name it is being defined as, so that
+// it shows up in error stack traces
+pub fn named(f: MalVal, name: &str) -> MalVal {
+    match *f {
+        MalFunc(ref mfd, ref meta) if mfd.name.is_empty() => {
+            let mut new_mfd = mfd.clone();
+            new_mfd.name = name.to_string();
+            malfuncd(new_mfd, meta.clone())
+        },
+        _ => f.clone(),
+    }
+}
+
+// The symbol a def! defines and the metadata given to it, which the
+// reader turns ^{...} sym into (with-meta sym {...}) for
+pub fn def_target(form: &MalVal) -> Option<(MalVal, MalVal)> {
+    match **form {
+        Sym(_) => Some((form.clone(), _nil())),
+        List(ref l,_) if l.len() == 3 => match (&*l[0], &*l[1], &*l[2]) {
+            (Sym(wm), &Sym(_), &Hash_Map(_,_)) if wm == "with-meta" => {
+                Some((l[1].clone(), l[2].clone()))
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+pub fn is_dynamic(meta: &MalVal) -> bool {
+    match **meta {
+        Hash_Map(ref hm,_) => match hm.get(&keyword("dynamic")) {
+            Some(v) => !matches!(**v, False | Nil),
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+// (binding [var val ...] body ...): give dynamic vars new values while
+// the body is evaluated, then put the old ones back however it exits
+fn eval_binding(args: &MalVec, env: Env) -> MalRet {
+    let binds = match *args[1] {
+        List(ref binds,_) | Vector(ref binds,_) => binds.clone(),
+        _ => return err_str("binding requires a vector of var/value pairs"),
+    };
+    let mut vars = vec![];
+    let mut it = binds.iter();
+    while let (Some(sym), Some(exp)) = (it.next(), it.next()) {
+        let (var_env, name) = dynamic_var(&env, sym)?;
+        let val = walk(exp.clone(), env.clone())?;
+        vars.push((var_env, name, val));
+    }
+    let mut body = vec![symbol("do")];
+    body.extend(args.iter().skip(2).cloned());
+    with_bindings(vars, || walk(list(body), env))
+}
+
+// The env and name of the var sym refers to, which must be dynamic
+pub fn dynamic_var(env: &Env, sym: &MalVal) -> Result<(Env, MalVal), MalError> {
+    let (var_env, name) = match namespace::find_var(env, sym)? {
+        Some(var) => var,
+        None => return Err(MalError::runtime(format!("'{}' not found", sym.pr_str(false)))),
+    };
+    if !env_is_dynamic(&var_env, &name) {
+        return Err(MalError::runtime(format!("Can't dynamically bind non-dynamic var: {}",
+                                             sym.pr_str(false))));
+    }
+    Ok((var_env, name))
+}
+
+// Call body with each (env, name, value) of vars bound. The bindings
+// are seen by the current thread only, and end with the call however
+// it exits.
+pub fn with_bindings<F>(vars: Vec<(Env, MalVal, MalVal)>, body: F) -> MalRet
+    where F: FnOnce() -> MalRet
+{
+    let _bound = env_bind_dynamic(vars);
+    body()
+}
+
+// Bind the value of a def! in env
+pub fn define(env: &Env, sym: &MalVal, meta: &MalVal, val: MalVal) -> MalRet {
+    match **sym {
+        Sym(ref name) => {
+            let val = named(val, name);
+            redefine(env, sym);
+            env_set(env, sym.clone(), val.clone());
+            env_set_dynamic(env, sym, is_dynamic(meta));
+            Ok(val)
+        },
+        _ => err_str("def! of non-symbol"),
+    }
+}
+
+// Bind a macro made from the value of a defmacro! in env
+pub fn define_macro(env: &Env, sym: &MalVal, val: MalVal) -> MalRet {
+    match *val {
+        MalFunc(ref mfd,_) => {
+            match **sym {
+                Sym(ref name) => {
+                    let mut new_mfd = mfd.clone();
+                    new_mfd.is_macro = true;
+                    if new_mfd.name.is_empty() {
+                        new_mfd.name = name.to_string();
+                    }
+                    let mf = malfuncd(new_mfd,_nil());
+                    redefine(env, sym);
+                    env_set(env, sym.clone(), mf.clone());
+                    Ok(mf)
+                },
+                _ => err_str("def! of non-symbol"),
+            }
+        },
+        _ => err_str("defmacro! of non-function"),
+    }
+}
+
+// Where a recur in tail position jumps back to: the body of the
+// enclosing loop* or fn*, rebound in a fresh child of env
+struct RecurPoint {
+    params: MalVal,
+    body:   MalVal,
+    env:    Env,
+}
+
+// (fn* ([x] ...) ([x y] ...)) rather than (fn* [x] ...): every clause
+// is a list starting with a parameter list
+pub fn is_multi_arity(args: &MalVec) -> bool {
+    args.len() > 1 && args.iter().skip(1).all(|clause| match **clause {
+        List(ref c,_) => matches!(c.get(0).map(|p| &**p), Some(&List(_,_)) | Some(&Vector(_,_))),
+        _ => false,
+    })
+}
+
+// The parameters and body of each clause of a multi-arity fn*, which
+// must not leave it ambiguous which clause a call is for
+pub fn arities(args: &MalVec) -> Result<Vec<(MalVal, MalVal)>, MalError> {
+    let mut arities = vec![];
+    let mut fixed_counts = vec![];
+    let mut variadic = None;
+    for clause in args.iter().skip(1) {
+        let c = match **clause {
+            List(ref c,_) if c.len() == 2 => c,
+            _ => return Err(MalError::runtime("fn* clause must be a parameter list and a body".to_string())),
+        };
+        match param_arity(&c[0]) {
+            (n, true) => {
+                if variadic.is_some() {
+                    return Err(MalError::runtime("fn* can't have more than one variadic arity".to_string()));
+                }
+                variadic = Some(n);
+            },
+            (n, false) => {
+                if fixed_counts.contains(&n) {
+                    return Err(MalError::runtime(format!("fn* can't have two arities taking {} args", n)));
+                }
+                fixed_counts.push(n);
+            },
+        }
+        arities.push((c[0].clone(), c[1].clone()));
+    }
+    if let Some(n) = variadic {
+        if fixed_counts.iter().any(|&f| f > n) {
+            return Err(MalError::runtime("fn* can't have a fixed arity with more params than the variadic one".to_string()));
+        }
+    }
+    Ok(arities)
+}
+
+fn multi_arity_fn(args: &MalVec, env: Env) -> MalRet {
+    let arities = arities(args)?;
+    let (params, exp) = arities[0].clone();
+    Ok(malfuncd(MalFuncData{name: String::new(),
+                            eval: eval_body,
+                            exp,
+                            env,
+                            params,
+                            is_macro: false,
+                            arities}, _nil()))
+}
+
+// Check the shape of a special form before evaluating it, so that
+// malformed code raises a mal error rather than panicking
+pub fn check_syntax(form: &str, args: &MalVec) -> Result<(), MalError> {
+    let nargs = args.len() - 1;
+    let (min, max) = match form {
+        "def!" | "defmacro!" | "let*" | "loop*" => (2, 2),
+        "quote" | "quasiquote" | "macroexpand" | "eval" => (1, 1),
+        "try*" => (1, 2),
+        "if" => (2, 3),
+        "binding" => (1, usize::MAX),
+        "fn*" if is_multi_arity(args) => (1, usize::MAX),
+        "fn*" => (2, 2),
+        _ => return Ok(()),
+    };
+    if nargs < min || nargs > max {
+        let expected = if min == max {
+            min.to_string()
+        } else if max == usize::MAX {
+            format!("{} or more", min)
+        } else {
+            format!("{} or {}", min, max)
+        };
+        return Err(MalError::runtime(format!("Wrong number of args ({}) passed to {}, expected {}",
+                                             nargs, form, expected)));
+    }
+    let problem = match form {
+        "def!" if def_target(&args[1]).is_some() => return Ok(()),
+        "def!" | "defmacro!" => match *args[1] {
+            Sym(_) => return Ok(()),
+            _ => "requires a symbol to define",
+        },
+        "binding" => match *args[1] {
+            List(ref binds,_) | Vector(ref binds,_) if binds.len() % 2 == 0 => return Ok(()),
+            _ => "requires a vector of var/value pairs",
+        },
+        "let*" | "loop*" => match *args[1] {
+            List(ref binds,_) | Vector(ref binds,_) if binds.len() % 2 == 0 => return Ok(()),
+            List(_,_) | Vector(_,_) => "bindings must have an even number of forms",
+            _ => "bindings must be a list or vector",
+        },
+        "try*" if nargs == 2 => match *args[2] {
+            List(ref cat,_) if cat.len() == 3 => match (&*cat[0], &*cat[1]) {
+                (Sym(c), &Sym(_)) if c == "catch*" => return Ok(()),
+                _ => "handler must be (catch* symbol body)",
+            },
+            _ => "handler must be (catch* symbol body)",
+        },
+        "fn*" if !is_multi_arity(args) => match *args[1] {
+            List(ref params,_) | Vector(ref params,_) => return check_params(params),
+            _ => "parameters must be a list or vector",
+        },
+        "fn*" => {
+            for clause in args.iter().skip(1) {
+                if let List(ref c,_) = **clause {
+                    if let List(ref params,_) | Vector(ref params,_) = *c[0] {
+                        check_params(params)?;
+                    }
+                }
+            }
+            return Ok(());
+        },
+        _ => return Ok(()),
+    };
+    Err(MalError::runtime(format!("{} {}", form, problem)))
+}
+
+// Each parameter of a fn* must be a symbol or a destructuring pattern,
+// with a pattern after & and a symbol after :as
+fn check_params(params: &MalVec) -> Result<(), MalError> {
+    match bad_param(params) {
+        Some(p) => Err(MalError::runtime(format!("fn* parameter {} is not a symbol or destructuring pattern",
+                                                 p.pr_str(true)))),
+        None => Ok(()),
+    }
+}
+
+fn bad_param(params: &MalVec) -> Option<MalVal> {
+    let mut it = params.iter();
+    while let Some(p) = it.next() {
+        let ok = match **p {
+            Sym(ref strn) if *strn == "&" => continue,
+            Keyword(ref k) if &**k == "as" => match it.next() {
+                Some(sym) => match **sym {
+                    Sym(_) => true,
+                    _ => return Some(sym.clone()),
+                },
+                None => true,
+            },
+            Sym(_) | Hash_Map(_,_) => true,
+            List(ref pats,_) | Vector(ref pats,_) => match bad_param(pats) {
+                Some(bad) => return Some(bad),
+                None => true,
+            },
+            _ => false,
+        };
+        if !ok {
+            return Some(p.clone());
+        }
+    }
+    None
+}
+
+pub fn eval(ast: MalVal, env: Env) -> MalRet {
+    match mode() {
+        Mode::Tree => walk(ast, env),
+        Mode::Bytecode => vm::eval(ast, env),
+    }
+}
+
+// Evaluate a form with the tree-walker, whatever the mode
+pub fn walk(ast: MalVal, env: Env) -> MalRet {
+    eval_call(ast, env, false)
+}
+
+// Evaluates the body of a fn* for MalType::apply, which rebinds the
+// arguments of a recur in tail position and evaluates it again
+fn eval_body(ast: MalVal, env: Env) -> MalRet {
+    eval_call(ast, env, true)
+}
+
+fn eval_call(ast: MalVal, env: Env, fn_body: bool) -> MalRet {
+    // errors are reported at the innermost form with a known position,
+    // and pass through the frame of the function whose body was being
+    // evaluated (tail calls replace the frame, so only the last shows)
+    let _depth = nest(1);
+    let mut pos = None;
+    let mut frame = None;
+    eval_tco(ast, env, &mut pos, &mut frame).map_err(|e| {
+        let e = match e.kind {
+            ErrorKind::Recur if fn_body => return e,
+            ErrorKind::Recur => MalError::runtime(e.message()),
+            _ => e,
+        };
+        let mut e = match pos {
+            Some(p) => e.at(p),
+            None => e,
+        };
+        if let Some(f) = frame {
+            e.stack.push(f);
+        }
+        e
+    })
+}
+
+fn eval_tco(mut ast: MalVal, mut env: Env,
+            pos: &mut Option<Pos>, frame: &mut Option<Frame>) -> MalRet {
+    // anything evaluated by a nested eval call is not in tail position,
+    // so only forms reached through 'tco can recur to this point
+    let mut recur_point: Option<RecurPoint> = None;
+    'tco: loop {
+    budget::step()?;
+
+    //println!("eval: {}, {}", ast, env.borrow());
+    //println!("eval: {}", ast);
+    if let Expanded(ref site) = *ast.clone() {
+        if let Some(p) = form_pos(&site.form) {
+            *pos = Some(p);
+        }
+        ast = analyzer::expansion(site, &env)?;
+    }
+    match *ast {
+        List(_,_) => (),  // continue
+        _ => return eval_ast(ast, env),
+    }
+    if let Some(p) = form_pos(&ast) {
+        *pos = Some(p);
+    }
+
+    let tmp = ast;
+    let (args, a0sym) = match *tmp {
+        List(ref args,_) => {
+            if args.is_empty() {
+                return Ok(tmp.clone());
+            }
+            let a0 = &*args[0];
+            match *a0 {
+                Sym(ref a0sym) => (args, &a0sym[..]),
+                _ => (args, "__<fn*>__"),
+            }
+        },
+        _ => return err_str("Expected list"),
+    };
+
+    check_syntax(a0sym, args)?;
+    match a0sym {
+        "def!" => {
+            let (a1, meta) = match def_target(&args[1]) {
+                Some(target) => target,
+                None => return err_str("def! of non-symbol"),
+            };
+            let a2 = (*args)[2].clone();
+            let r = walk(a2, env.clone())?;
+            return define(&env, &a1, &meta, r);
+        },
+        "binding" => {
+            return eval_binding(args, env);
+        },
+        "let*" => {
+            let let_env = env_new(Some(env.clone()));
+            let a1 = (*args)[1].clone();
+            let a2 = (*args)[2].clone();
+            match *a1 {
+                List(ref binds,_) | Vector(ref binds,_) => {
+                    let mut it = binds.iter();
+                    while it.len() >= 2 {
+                        let b = it.next().unwrap();
+                        let exp = it.next().unwrap();
+                        let r = walk(exp.clone(), let_env.clone())?;
+                        if let Err(e) = env_destructure(&let_env, b, r) {
+                            return err_string(e);
+                        }
+                    }
+                },
+                _ => return err_str("let* with non-list bindings"),
+            }
+            ast = a2;
+            env = let_env.clone();
+            continue 'tco;
+        },
+        "loop*" => {
+            let loop_env = env_new(Some(env.clone()));
+            let a1 = (*args)[1].clone();
+            let a2 = (*args)[2].clone();
+            let mut params = vec![];
+            match *a1 {
+                List(ref binds,_) | Vector(ref binds,_) => {
+                    let mut it = binds.iter();
+                    while it.len() >= 2 {
+                        let b = it.next().unwrap();
+                        let exp = it.next().unwrap();
+                        let r = walk(exp.clone(), loop_env.clone())?;
+                        if let Err(e) = env_destructure(&loop_env, b, r) {
+                            return err_string(e);
+                        }
+                        params.push(b.clone());
+                    }
+                },
+                _ => return err_str("loop* with non-list bindings"),
+            }
+            recur_point = Some(RecurPoint{params: list(params), body: a2.clone(), env});
+            ast = a2;
+            env = loop_env;
+            continue 'tco;
+        },
+        "recur" => {
+            let el = eval_ast(list(args.skip(1)), env.clone())?;
+            let point = match recur_point {
+                Some(ref point) => point,
+                None => return Err(MalError::new(ErrorKind::Recur, el)),
+            };
+            let new_env = env_new(Some(point.env.clone()));
+            match env_bind_recur(&new_env, point.params.clone(), el) {
+                Ok(_) => {
+                    ast = point.body.clone();
+                    env = new_env;
+                    continue 'tco;
+                },
+                Err(e) => return err_str(&e),
+            }
+        },
+        "quote" => return Ok((*args)[1].clone()),
+        "quasiquote" => {
+            let a1 = (*args)[1].clone();
+            ast = quasiquote(a1)?;
+            continue 'tco;
+        },
+        "defmacro!" => {
+            let a1 = (*args)[1].clone();
+            let a2 = (*args)[2].clone();
+            let r = walk(a2, env.clone())?;
+            return define_macro(&env, &a1, r);
+        },
+        "macroexpand" => {
+            let a1 = (*args)[1].clone();
+            return macroexpand(a1, env.clone())
+        },
+        "try*" => {
+            let a1 = (*args)[1].clone();
+            match walk(a1, env.clone()) {
+                Ok(res) => return Ok(res),
+                Err(err) => {
+                    if args.len() < 3 || err.kind == ErrorKind::Budget {
+                        return Err(err);
+                    }
+                    let a2 = (*args)[2].clone();
+                    let cat = match *a2 {
+                        List(ref cat,_) => cat,
+                        _ => return err_str("invalid catch* clause"),
+                    };
+                    let c1 = (*cat)[1].clone();
+                    let exc = err.to_val();
+                    let bind_env = env_new(Some(env.clone()));
+                    env_bind_local(&bind_env, c1.clone(), exc);
+                    let c2 = (*cat)[2].clone();
+                    return walk(c2, bind_env);
+                },
+            };
+        }
+        "do" => {
+            if args.len() == 1 {
+                return Ok(_nil());
+            }
+            let el = list(args.skip(1).take(args.len()-2));
+            eval_ast(el, env.clone())?;
+            ast = args[args.len() - 1].clone();
+            continue 'tco;
+        },
+        "if" => {
+            let a1 = (*args)[1].clone();
+            let c = walk(a1, env.clone())?;
+            match *c {
+                False | Nil => {
+                    if args.len() >= 4 {
+                        ast = args[3].clone();
+                        continue 'tco;
+                    } else {
+                        return Ok(_nil());
+                    }
+                },
+                _ => {
+                    ast = args[2].clone();
+                    continue 'tco;
+                },
+            }
+        },
+        "fn*" => {
+            let form = analyzer::analyze_fn(&tmp, &env);
+            let args = match *form {
+                List(ref args,_) => args,
+                _ => return err_str("Expected list"),
+            };
+            if is_multi_arity(args) {
+                return multi_arity_fn(args, env);
+            }
+            let a1 = args[1].clone();
+            let a2 = args[2].clone();
+            return Ok(malfunc(eval_body, a2, env, a1, _nil()));
+        },
+        "eval" => {
+            let a1 = (*args)[1].clone();
+            ast = walk(a1, env.clone())?;
+            env = namespace::eval_env(&env);
+            continue 'tco;
+        },
+        _ => { // function call, unless the head names a macro
+            let f = walk(args[0].clone(), env.clone())?;
+            if let MalFunc(ref mfd,_) = *f {
+                if mfd.is_macro && matches!(*args[0], Sym(_)) {
+                    ast = macroexpand(tmp.clone(), env.clone())?;
+                    continue 'tco;
+                }
+            }
+            let mut el = MalVec::new();
+            el.push_back(f);
+            for arg in args.iter().skip(1) {
+                el.push_back(walk(arg.clone(), env.clone())?);
+            }
+            let args = el;
+            return match *args.clone()[0] {
+                Func(ref f,_) => {
+                    let fargs: Vec<MalVal> = args.iter().skip(1).cloned().collect();
+                    budget::charged(&*f.f, fargs)
+                        .map_err(|e| e.in_frame(&f.name, pos.clone()))
+                },
+                MalFunc(ref mf,_) => {
+                    let mfc = mf.clone();
+                    let arity = check_depth(&mfc.name).and_then(|_| mfc.arity(args.len() - 1));
+                    let (params, exp) = match arity {
+                        Ok(arity) => arity,
+                        Err(e) => return Err(e.in_frame(&mfc.name, pos.clone())),
+                    };
+                    let alst = list(args.skip(1));
+                    let new_env = env_new(Some(mfc.env.clone()));
+                    match env_bind(&new_env, params.clone(), alst) {
+                        Ok(_) => {
+                            *frame = Some(Frame{name: mfc.name, pos: pos.clone()});
+                            recur_point = Some(RecurPoint{params,
+                                                          body: exp.clone(),
+                                                          env: mfc.env});
+                            ast = exp;
+                            env = new_env;
+                            continue 'tco;
+                        },
+                        Err(e) => Err(MalError::runtime(e).in_frame(&mfc.name, pos.clone())),
+                    }
+                },
+                _ => err_str("attempt to call non-function"),
+            }
+        },
+    }
+
+    }
+}
+
+
+// print
+pub fn print(exp: MalVal) -> String {
+    exp.pr_str(true)
+}
+
+pub fn rep(str: &str, env: Env) -> Result<String,MalError> {
+    let ast = read(str.to_string())?;
+    //println!("read: {}", ast);
+    let exp = eval(ast, env)?;
+    Ok(print(exp))
+}
@@ -0,0 +1,367 @@
+// Cycle collector for the envs and values that closures and atoms tie
+// together. A closure holds the env it was made in, which usually holds
+// the closure, so plain reference counting never frees either.
+//
+// The collector keeps weak references to every env a closure has been
+// made in and every atom, which between them are part of any cycle: no
+// other value can refer to something made after it. From those it finds
+// the graph of envs and values they reach, and subtracts the references
+// the graph holds to itself from each one's reference count. Whatever
+// is left with references from outside (the Rust stack, a namespace
+// registry, an embedder) is live, along with everything it reaches;
+// the envs and atoms in the rest are emptied, which frees the rest.
+//
+// References held where the collector can't see them are counted as
+// being from outside, so they keep what they refer to alive. That
+// includes the elements of lists and vectors that share structure with
+// another one and the contents of hash-maps, whose internal nodes may
+// be shared, so cycles through those are not collected.
+//
+// Each thread tracks what it makes, and an Interpreter swaps in what
+// it has tracked while it evaluates (see interpreter). The reference
+// counts the collector works from must not change under it, so with
+// the "threads" feature nothing is collected while a future is queued
+// or running (see threads), or while another thread is evaluating
+// through an Interpreter. A host thread that changes values or envs
+// without going through an Interpreter, such as by setting an atom it
+// holds directly, is not seen: it must not do so while another thread
+// may be collecting.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use sync::{self, Rc, Weak};
+use types::{self, MalVal, MalType, _nil};
+use types::MalType::{List, Vector, Hash_Map, Func, MalFunc, Atom, ExInfo};
+use env::{env_each_ref, env_clear, EnvRef, EnvType, Env};
+#[cfg(feature = "threads")]
+use threads;
+
+// Collect once this many closures and atoms have been made since the
+// last collection, or as many as survived it if that is more
+const MIN_THRESHOLD: usize = 10000;
+
+/// What the collector tracks and has done on the current thread.
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    /// Live envs that closures have been made in
+    pub envs: usize,
+    /// Live atoms
+    pub atoms: usize,
+    /// Collections run so far
+    pub collections: u64,
+    /// Envs and atoms emptied by collections, freeing them and what
+    /// only they referred to
+    pub freed: u64,
+    /// Symbols interned, by every thread with the "threads" feature.
+    /// Those nothing refers to are dropped as more are made.
+    pub symbols: usize,
+}
+
+struct Tracked {
+    envs:        Vec<Weak<sync::RefCell<EnvType>>>,
+    atoms:       Vec<Weak<MalType>>,
+    made:        usize,  // since the last collection
+    threshold:   usize,
+    prune_at:    usize,  // length at which to drop dead weak references
+    collections: u64,
+    freed:       u64,
+}
+
+impl Default for Tracked {
+    fn default() -> Tracked {
+        Tracked{envs: vec![], atoms: vec![], made: 0, threshold: MIN_THRESHOLD,
+                prune_at: MIN_THRESHOLD, collections: 0, freed: 0}
+    }
+}
+
+thread_local! {
+    static TRACKED: RefCell<Tracked> = RefCell::new(Tracked::default());
+}
+
+/// What an `Interpreter` has tracked, kept by it while it isn't
+/// evaluating (see `swap`).
+#[derive(Default)]
+pub struct Saved(Tracked);
+
+impl Saved {
+    // Take on what other tracked
+    pub fn merge(&mut self, other: Saved) {
+        let (t, other) = (&mut self.0, other.0);
+        t.envs.extend(other.envs);
+        t.atoms.extend(other.atoms);
+        t.made += other.made;
+        t.collections += other.collections;
+        t.freed += other.freed;
+    }
+}
+
+// Exchange what the current thread tracks with saved
+pub fn swap(saved: &mut Saved) {
+    TRACKED.with(|t| mem::swap(&mut *t.borrow_mut(), &mut saved.0));
+}
+
+impl Tracked {
+    fn prune(&mut self) {
+        self.envs.retain(|e| e.strong_count() > 0);
+        self.atoms.retain(|a| a.strong_count() > 0);
+        self.prune_at = MIN_THRESHOLD.max(2 * (self.envs.len() + self.atoms.len()));
+    }
+
+    fn made_one(&mut self) {
+        self.made += 1;
+        if self.envs.len() + self.atoms.len() >= self.prune_at {
+            self.prune();
+        }
+    }
+}
+
+// Called with the env of each closure made
+pub fn track_env(env: &Env) {
+    TRACKED.with(|t| {
+        let mut t = t.borrow_mut();
+        t.envs.push(Rc::downgrade(env));
+        t.made_one();
+    });
+}
+
+// Called with each atom made
+pub fn track_atom(atom: &MalVal) {
+    TRACKED.with(|t| {
+        let mut t = t.borrow_mut();
+        t.atoms.push(Rc::downgrade(atom));
+        t.made_one();
+    });
+}
+
+/// Counts of what is tracked and collected on the current thread.
+pub fn stats() -> Stats {
+    TRACKED.with(|t| {
+        let t = t.borrow();
+        let envs: HashSet<*const sync::RefCell<EnvType>> = t.envs.iter()
+            .filter(|e| e.strong_count() > 0)
+            .map(|e| e.as_ptr())
+            .collect();
+        Stats{envs: envs.len(),
+              atoms: t.atoms.iter().filter(|a| a.strong_count() > 0).count(),
+              collections: t.collections,
+              freed: t.freed,
+              symbols: types::symbol_count()}
+    })
+}
+
+// Threads evaluating through an Interpreter
+static EVALUATING: AtomicUsize = AtomicUsize::new(0);
+
+/// Held by a thread while it evaluates through an `Interpreter`, so
+/// that no other thread collects meanwhile.
+pub struct Evaluating(());
+
+pub fn evaluating() -> Evaluating {
+    EVALUATING.fetch_add(1, Ordering::SeqCst);
+    Evaluating(())
+}
+
+impl Drop for Evaluating {
+    fn drop(&mut self) {
+        EVALUATING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Collect if enough closures and atoms have been made since the last
+/// collection. Returns the number of envs and atoms freed.
+pub fn maybe_collect() -> usize {
+    let due = TRACKED.with(|t| {
+        let t = t.borrow();
+        t.made >= t.threshold
+    });
+    if due { collect() } else { 0 }
+}
+
+/// Free the envs and atoms on the current thread that are only
+/// reachable from each other. Returns the number freed.
+pub fn collect() -> usize {
+    #[cfg(feature = "threads")]
+    {
+        // this thread may be evaluating itself
+        if threads::busy() || EVALUATING.load(Ordering::SeqCst) > 1 {
+            return 0;
+        }
+    }
+    let mut graph = Graph{nodes: vec![], refs: vec![], index: HashMap::new()};
+    TRACKED.with(|t| {
+        let t = t.borrow();
+        for env in t.envs.iter().filter_map(|e| e.upgrade()) {
+            graph.add(Node::Env(env));
+        }
+        for atom in t.atoms.iter().filter_map(|a| a.upgrade()) {
+            graph.add(Node::Val(atom));
+        }
+    });
+
+    // subtract the references nodes hold to each other, adding the
+    // nodes they refer to as they are found
+    let mut i = 0;
+    while i < graph.nodes.len() {
+        let node = graph.nodes[i].clone();
+        if !each_ref(&node, |r| graph.referenced(r)) {
+            graph.refs[i] = isize::MAX;  // can't be looked at: keep it
+        }
+        i += 1;
+    }
+
+    // mark what is reachable from nodes referenced from outside
+    let mut live = vec![false; graph.nodes.len()];
+    let mut stack: Vec<usize> = (0..graph.nodes.len()).filter(|&i| graph.refs[i] > 0).collect();
+    while let Some(i) = stack.pop() {
+        if live[i] {
+            continue;
+        }
+        live[i] = true;
+        each_ref(&graph.nodes[i], |r| {
+            if let Some(&j) = graph.index.get(&r.key()) {
+                if !live[j] {
+                    stack.push(j);
+                }
+            }
+        });
+    }
+
+    // empty the rest, dropping what they held once nothing is borrowed
+    let mut envs: Vec<EnvType> = vec![];
+    let mut vals: Vec<MalVal> = vec![];
+    for (node, _) in graph.nodes.iter().zip(live).filter(|&(_, live)| !live) {
+        match *node {
+            Node::Env(ref env) => envs.push(env_clear(env)),
+            Node::Val(ref val) => if let Atom(ref cell) = **val {
+                vals.push(mem::replace(&mut *cell.borrow_mut(), _nil()));
+            },
+        }
+    }
+    let freed = envs.len() + vals.len();
+    drop(envs);
+    drop(vals);
+    drop(graph);
+
+    TRACKED.with(|t| {
+        let mut t = t.borrow_mut();
+        t.prune();
+        t.made = 0;
+        t.threshold = MIN_THRESHOLD.max(t.envs.len() + t.atoms.len());
+        t.collections += 1;
+        t.freed += freed as u64;
+    });
+    freed
+}
+
+#[derive(Clone)]
+enum Node {
+    Env(Env),
+    Val(MalVal),
+}
+
+// A reference from one node to another
+enum Ref<'a> {
+    Env(&'a Env),
+    Val(&'a MalVal),
+}
+
+impl<'a> Ref<'a> {
+    fn key(&self) -> usize {
+        match *self {
+            Ref::Env(env) => &**env as *const sync::RefCell<EnvType> as usize,
+            Ref::Val(val) => &**val as *const MalType as usize,
+        }
+    }
+}
+
+struct Graph {
+    nodes: Vec<Node>,
+    refs:  Vec<isize>,  // references to each node from outside the graph
+    index: HashMap<usize, usize>,
+}
+
+impl Graph {
+    fn add(&mut self, node: Node) -> usize {
+        let (key, count) = match node {
+            Node::Env(ref env) => (&**env as *const sync::RefCell<EnvType> as usize,
+                                   Rc::strong_count(env)),
+            Node::Val(ref val) => (&**val as *const MalType as usize, Rc::strong_count(val)),
+        };
+        if let Some(&i) = self.index.get(&key) {
+            return i;
+        }
+        // not counting the reference in nodes
+        self.nodes.push(node);
+        self.refs.push(count as isize - 1);
+        self.index.insert(key, self.nodes.len() - 1);
+        self.nodes.len() - 1
+    }
+
+    fn referenced(&mut self, r: Ref) {
+        let node = match r {
+            Ref::Env(env) => Node::Env(env.clone()),
+            Ref::Val(val) if may_refer(val) => Node::Val(val.clone()),
+            Ref::Val(_) => return,
+        };
+        let i = self.add(node);
+        self.refs[i] -= 1;
+    }
+}
+
+// Whether a value can hold references to envs or atoms
+fn may_refer(val: &MalVal) -> bool {
+    matches!(**val, List(_,_) | Vector(_,_) | Hash_Map(_,_) | Func(_,_) | MalFunc(_,_) |
+                    Atom(_) | ExInfo(_,_,_))
+}
+
+// Call f with each reference node holds that the collector can see.
+// Returns false if node is borrowed for changing and can't be looked at.
+fn each_ref<F>(node: &Node, mut f: F) -> bool
+    where F: FnMut(Ref)
+{
+    let val = match *node {
+        Node::Env(ref env) => {
+            return env_each_ref(env, |r| match r {
+                EnvRef::Val(val) => f(Ref::Val(val)),
+                EnvRef::Outer(outer) => f(Ref::Env(outer)),
+            });
+        },
+        Node::Val(ref val) => val,
+    };
+    match **val {
+        List(ref items, ref meta) | Vector(ref items, ref meta) => {
+            // a vector that isn't inline may share its elements'
+            // references with others
+            if items.is_inline() {
+                for item in items.iter() {
+                    f(Ref::Val(item));
+                }
+            }
+            f(Ref::Val(meta));
+        },
+        Hash_Map(_, ref meta) | Func(_, ref meta) => f(Ref::Val(meta)),
+        MalFunc(ref mfd, ref meta) => {
+            f(Ref::Env(&mfd.env));
+            f(Ref::Val(&mfd.exp));
+            f(Ref::Val(&mfd.params));
+            for (params, exp) in mfd.arities.iter() {
+                f(Ref::Val(params));
+                f(Ref::Val(exp));
+            }
+            f(Ref::Val(meta));
+        },
+        Atom(ref cell) => match cell.try_borrow() {
+            Ok(val) => f(Ref::Val(&val)),
+            Err(_) => return false,
+        },
+        ExInfo(_, ref data, ref cause) => {
+            f(Ref::Val(data));
+            f(Ref::Val(cause));
+        },
+        _ => (),
+    }
+    true
+}
@@ -0,0 +1,331 @@
+use std::any::Any;
+use std::cell::Cell;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+
+use types::{MalVal, MalRet, MalError, symbol, _nil, string, list, native_fn};
+use env::{env_set, Env};
+use eval;
+use gc;
+use budget::{self, Budget};
+use reader;
+use sandbox::Capabilities;
+use namespace::{self, RegistryRef};
+use core;
+use sync::{Shared, RefCell};
+
+// Bootstrap definitions written in mal itself (see stepA_mal)
+static PRELUDE: &[&str] = &[
+    "(def! *host-language* \"rust\")",
+    "(def! not (fn* (a) (if a false true)))",
+    "(defmacro! cond (fn* (& xs) (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw \"odd number of forms to cond\")) (cons 'cond (rest (rest xs)))))))",
+    "(def! *gensym-counter* (atom 0))",
+    "(def! gensym (fn* [] (symbol (str \"G__\" (swap! *gensym-counter* (fn* [x] (+ 1 x)))))))",
+    "(defmacro! ns (fn* (name & clauses) `(do (in-ns '~name) ~@(map (fn* (c) (if (= :require (first c)) (cons 'require (map (fn* (s) (list 'quote s)) (rest c))) (throw (str \"unsupported ns clause \" (pr-str c))))) clauses) nil)))",
+    "(defmacro! or (fn* (& xs) (if (empty? xs) nil (if (= 1 (count xs)) (first xs) (let* (condvar (gensym)) `(let* (~condvar ~(first xs)) (if ~condvar ~condvar (or ~@(rest xs)))))))))",
+];
+
+// Bootstrap definitions for the "threads" feature (see threads)
+#[cfg(feature = "threads")]
+static THREADS_PRELUDE: &[&str] = &[
+    "(defmacro! future (fn* (& body) `(future-call (fn* [] ~@body))))",
+];
+
+/// A mal interpreter with its own set of namespaces.
+///
+/// The `mal.core` namespace is seeded from `core::ns()`, the namespace
+/// functions (`in-ns`, `require`, `load-file`) and `*file*`, and the mal
+/// defined bootstrap functions and macros (`not`, `cond`, `gensym`,
+/// `ns`, `or`), so it behaves like the stepA REPL. Evaluation starts out in the
+/// `user` namespace, whose outer environment is `mal.core`.
+///
+/// Use `with_capabilities` to limit what the I/O built-ins may do when
+/// running untrusted code.
+///
+/// The eval mode, maximum depth, budget and what the cycle collector
+/// tracks belong to the interpreter: they are installed on the calling
+/// thread for the duration of `eval` and `rep`, so two interpreters on
+/// one thread don't share them.
+pub struct Interpreter {
+    registry: RegistryRef,
+    settings: RefCell<Settings>,
+}
+
+struct Settings {
+    mode:      eval::Mode,
+    max_depth: usize,
+    budget:    Budget,
+    // What has been counted against the budget and what the collector
+    // tracks, or None while a thread is evaluating with them
+    state:     Option<(budget::Saved, gc::Saved)>,
+    // What the collector tracked for threads that evaluated meanwhile
+    strays:    Vec<gc::Saved>,
+}
+
+thread_local! {
+    // The registry of the interpreter evaluating on this thread, if any
+    static CURRENT: Cell<usize> = const { Cell::new(0) };
+}
+
+// The thread's settings from before an interpreter installed its own,
+// which are put back when it is dropped
+struct Entered<'a> {
+    interp:    &'a Interpreter,
+    mode:      eval::Mode,
+    max_depth: usize,
+    budget:    budget::Saved,
+    gc:        gc::Saved,
+    current:   usize,
+    owned:     bool,  // whether the state was taken from settings
+    _evaluating: gc::Evaluating,
+}
+
+impl<'a> Drop for Entered<'a> {
+    fn drop(&mut self) {
+        budget::swap(&mut self.budget);
+        gc::swap(&mut self.gc);
+        eval::set_mode(self.mode);
+        eval::set_max_depth(self.max_depth);
+        CURRENT.with(|c| c.set(self.current));
+        let budget = mem::take(&mut self.budget);
+        let gc = mem::take(&mut self.gc);
+        let mut settings = self.interp.settings.borrow_mut();
+        if self.owned {
+            settings.state = Some((budget, gc));
+        } else {
+            settings.strays.push(gc);
+        }
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        Interpreter::with_capabilities(&Capabilities::all())
+    }
+
+    /// An interpreter whose `slurp`, `load-file`, `readline`, `prn` and
+    /// `println` are restricted to `caps`. A denied operation raises an
+    /// error of kind `ErrorKind::Permission`.
+    pub fn with_capabilities(caps: &Capabilities) -> Interpreter {
+        let registry = namespace::new_registry();
+        let env = namespace::current_env(&registry);
+
+        // core.rs: defined using rust
+        for (k, v) in core::ns_with(caps).into_iter() {
+            env_set(&env, symbol(&k), v);
+        }
+        for (k, v) in namespace::builtins(&registry, caps).into_iter() {
+            env_set(&env, symbol(&k), v);
+        }
+        // see eval() for definition of "eval"
+        env_set(&env, symbol("*ARGV*"), list(vec![]));
+
+        // core.mal: defined using the language itself
+        let settings = Settings{mode: eval::Mode::Bytecode,
+                                max_depth: eval::DEFAULT_MAX_DEPTH,
+                                budget: Budget::default(),
+                                state: Some((budget::Saved::default(), gc::Saved::default())),
+                                strays: vec![]};
+        let interp = Interpreter { registry, settings: RefCell::new(settings) };
+        for src in PRELUDE.iter() {
+            interp.eval_str(src).expect("mal prelude failed to evaluate");
+        }
+        #[cfg(feature = "threads")]
+        for src in THREADS_PRELUDE.iter() {
+            interp.eval_str(src).expect("mal prelude failed to evaluate");
+        }
+        namespace::set_current_ns(&interp.registry, namespace::USER_NS);
+        interp
+    }
+
+    /// The environment of the current namespace, which forms passed to
+    /// `eval`, `eval_str` and `rep` are evaluated in.
+    pub fn env(&self) -> Env {
+        namespace::current_env(&self.registry)
+    }
+
+    /// Bind `name` to `val` in `mal.core`, so that every namespace
+    /// sees it.
+    pub fn define(&self, name: &str, val: MalVal) {
+        let core = namespace::ns_env(&self.registry, namespace::CORE_NS)
+            .expect("core namespace missing");
+        env_set(&core, symbol(name), val);
+    }
+
+    /// Bind `name` to a native function, which may be a closure
+    /// capturing host state (a counter, a database handle, ...). With
+    /// the `threads` feature it must be `Send + Sync`, as futures may
+    /// call it from other threads.
+    pub fn define_fn<F>(&self, name: &str, f: F)
+        where F: Fn(Vec<MalVal>) -> MalRet + Shared + 'static
+    {
+        self.define(name, native_fn(name, f));
+    }
+
+    /// Evaluate an already read form in the current namespace. As in
+    /// `rep`, a panic inside the interpreter is returned as an error.
+    ///
+    /// Afterwards, envs and atoms that only refer to each other are
+    /// freed if enough closures and atoms have been made since that
+    /// was last done (see `gc`).
+    pub fn eval(&self, ast: MalVal) -> MalRet {
+        let _entered = self.enter();
+        let env = self.env();
+        let res = guard(|| eval::eval(ast, env));
+        gc::maybe_collect();
+        res
+    }
+
+    fn id(&self) -> usize {
+        &*self.registry as *const _ as usize
+    }
+
+    fn is_current(&self) -> bool {
+        CURRENT.with(|c| c.get()) == self.id()
+    }
+
+    // Install this interpreter's settings on the current thread until
+    // the result is dropped, unless they already are. If another
+    // thread is evaluating with its budget and tracked values, this
+    // one starts a budget of its own, and what it tracks is taken on
+    // when the interpreter's own tracking is next installed.
+    fn enter(&self) -> Option<Entered<'_>> {
+        if self.is_current() {
+            return None;
+        }
+        let mut settings = self.settings.borrow_mut();
+        let (mut budget, mut gc, owned) = match settings.state.take() {
+            Some((budget, mut gc)) => {
+                for stray in settings.strays.drain(..) {
+                    gc.merge(stray);
+                }
+                (budget, gc, true)
+            },
+            None => (budget::Saved::new(&settings.budget), gc::Saved::default(), false),
+        };
+        budget::swap(&mut budget);
+        gc::swap(&mut gc);
+        let entered = Entered{interp: self,
+                              mode: eval::mode(),
+                              max_depth: eval::max_depth(),
+                              budget,
+                              gc,
+                              current: CURRENT.with(|c| c.get()),
+                              owned,
+                              _evaluating: gc::evaluating()};
+        eval::set_mode(settings.mode);
+        eval::set_max_depth(settings.max_depth);
+        CURRENT.with(|c| c.set(self.id()));
+        Some(entered)
+    }
+
+    /// Read and evaluate every form in `src`, returning the value of
+    /// the last one (or nil if there are none). An `ns` form switches
+    /// the namespace the forms after it are evaluated in.
+    pub fn eval_str(&self, src: &str) -> MalRet {
+        let mut res = _nil();
+        for ast in reader::read_all(src.to_string())? {
+            res = self.eval(ast)?;
+        }
+        Ok(res)
+    }
+
+    /// Set the directories, in order, that `require` looks for
+    /// namespace files in. The default is the working directory.
+    pub fn set_search_path(&self, path: Vec<PathBuf>) {
+        namespace::set_search_path(&self.registry, path);
+    }
+
+    /// Limit how deeply non-tail calls may nest before evaluation
+    /// fails with a "stack overflow" error. The thread that evaluates
+    /// must have room on its stack for that many levels.
+    pub fn set_max_depth(&self, depth: usize) {
+        self.settings.borrow_mut().max_depth = depth;
+        if self.is_current() {
+            eval::set_max_depth(depth);
+        }
+    }
+
+    /// Choose how forms are evaluated: compiled to bytecode for the VM
+    /// (the default), or by the tree-walker.
+    pub fn set_mode(&self, mode: eval::Mode) {
+        self.settings.borrow_mut().mode = mode;
+        if self.is_current() {
+            eval::set_mode(mode);
+        }
+    }
+
+    /// Limit the work done by this interpreter's evaluation from now
+    /// on. Exceeding the budget raises an error of kind
+    /// `ErrorKind::Budget`, which mal code cannot catch. Pass
+    /// `Budget::default()` to remove the limits.
+    pub fn set_budget(&self, budget: &Budget) {
+        let mut settings = self.settings.borrow_mut();
+        settings.budget = budget.clone();
+        if self.is_current() {
+            budget::set_budget(budget);
+        } else if let Some((ref mut saved, _)) = settings.state {
+            *saved = budget::Saved::new(budget);
+        }
+    }
+
+    /// Read, evaluate and print a single form, as the REPL does.
+    ///
+    /// As a last resort, a panic inside the interpreter is returned as
+    /// an error so that one bad form doesn't end the session.
+    pub fn rep(&self, src: &str) -> Result<String,MalError> {
+        let _entered = self.enter();
+        let env = self.env();
+        let res = guard(|| eval::rep(src, env));
+        gc::maybe_collect();
+        res
+    }
+
+    /// Free the envs and atoms made by this interpreter that are only
+    /// reachable from each other, such as a recursive function's env
+    /// and the function, as the mal function `gc` does. Returns the
+    /// number of envs and atoms freed.
+    pub fn gc(&self) -> usize {
+        let _entered = self.enter();
+        gc::collect()
+    }
+
+    /// What the cycle collector tracks and has freed for this
+    /// interpreter, which the mal function `memory-stats` returns as a
+    /// map.
+    pub fn memory_stats(&self) -> gc::Stats {
+        let _entered = self.enter();
+        gc::stats()
+    }
+
+    /// Evaluate the contents of the file at `path` using `load-file`.
+    pub fn load_file(&self, path: &str) -> MalRet {
+        self.eval(list(vec![symbol("load-file"), string(path.to_string())]))
+    }
+}
+
+// Run f, turning a panic into an error
+fn guard<T, F: FnOnce() -> Result<T,MalError>>(f: F) -> Result<T,MalError> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(res) => res,
+        Err(cause) => Err(MalError::runtime(format!("internal error: {}",
+                                                    panic_message(&cause)))),
+    }
+}
+
+pub fn panic_message(cause: &Box<dyn Any + Send>) -> String {
+    if let Some(s) = cause.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = cause.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "panic".to_string()
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        Interpreter::new()
+    }
+}
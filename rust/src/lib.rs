@@ -2,14 +2,29 @@ extern crate libc;
 extern crate regex;
 extern crate time;
 extern crate num;
+extern crate im_rc;
+#[cfg(feature = "threads")]
+extern crate im;
 
 macro_rules! regex {
     ($e:expr) => (::regex::Regex::new($e).unwrap())
 }
 
+pub mod analyzer;
+pub mod budget;
+pub mod compiler;
 pub mod core;
 pub mod env;
+pub mod eval;
+pub mod gc;
+pub mod interpreter;
+pub mod namespace;
 pub mod printer;
 pub mod reader;
 pub mod readline;
+pub mod sandbox;
+pub mod sync;
+#[cfg(feature = "threads")]
+pub mod threads;
 pub mod types;
+pub mod vm;
@@ -0,0 +1,373 @@
+// Namespaces. Each namespace is a top-level Env whose outer env is the
+// core namespace holding the built-ins, so an unqualified symbol is
+// looked up in its own namespace and then among the built-ins. Since a
+// function keeps the env it was defined in, it goes on resolving
+// symbols in its own namespace when called from another one.
+
+use std::collections::HashMap;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use sync::{Rc, Weak, RefCell};
+use types::{MalVal, MalRet, MalError, _nil, symbol, string, err_str, native_fn};
+use types::MalType::{Sym, Keyword, Strn, List, Vector};
+use env::{Env, env_new_ns, env_ns, env_root, env_find, env_get, env_set, env_symbols};
+use sandbox::Capabilities;
+use eval;
+use reader;
+
+pub const CORE_NS: &str = "mal.core";
+pub const USER_NS: &str = "user";
+
+struct Namespace {
+    env: Env,
+    aliases: HashMap<String,String>,
+}
+
+pub struct Registry {
+    namespaces: HashMap<String,Namespace>,
+    current: String,
+    loading: Vec<String>,   // namespaces being required, outermost first
+    file: Option<String>,   // the file being loaded, if any
+    path: Vec<PathBuf>,     // directories to look for namespaces in
+}
+
+pub type RegistryRef = Rc<RefCell<Registry>>;
+
+// Marks the top-level env of a namespace. The registry owns the env,
+// so the env only keeps a weak reference back to it.
+#[derive(Clone)]
+pub struct NsTag {
+    pub name: String,
+    registry: Weak<RefCell<Registry>>,
+}
+
+impl NsTag {
+    fn registry(&self) -> Option<RegistryRef> {
+        self.registry.upgrade()
+    }
+}
+
+// A registry holding just the (empty) core namespace, which is current
+pub fn new_registry() -> RegistryRef {
+    let reg = Rc::new(RefCell::new(Registry{namespaces: HashMap::new(),
+                                            current: CORE_NS.to_string(),
+                                            loading: vec![],
+                                            file: None,
+                                            path: vec![PathBuf::from(".")]}));
+    let tag = NsTag{name: CORE_NS.to_string(), registry: Rc::downgrade(&reg)};
+    reg.borrow_mut().namespaces.insert(CORE_NS.to_string(),
+                                       Namespace{env: env_new_ns(None, tag),
+                                                 aliases: HashMap::new()});
+    reg
+}
+
+pub fn ns_env(reg: &RegistryRef, name: &str) -> Option<Env> {
+    reg.borrow().namespaces.get(name).map(|ns| ns.env.clone())
+}
+
+pub fn find_or_create_ns(reg: &RegistryRef, name: &str) -> Env {
+    if let Some(env) = ns_env(reg, name) {
+        return env;
+    }
+    let core = ns_env(reg, CORE_NS).expect("core namespace missing");
+    let tag = NsTag{name: name.to_string(), registry: Rc::downgrade(reg)};
+    let env = env_new_ns(Some(core), tag);
+    reg.borrow_mut().namespaces.insert(name.to_string(),
+                                       Namespace{env: env.clone(),
+                                                 aliases: HashMap::new()});
+    env
+}
+
+pub fn current_ns(reg: &RegistryRef) -> String {
+    reg.borrow().current.clone()
+}
+
+pub fn set_current_ns(reg: &RegistryRef, name: &str) {
+    find_or_create_ns(reg, name);
+    reg.borrow_mut().current = name.to_string();
+}
+
+// The env top-level forms are evaluated in
+pub fn current_env(reg: &RegistryRef) -> Env {
+    let name = current_ns(reg);
+    find_or_create_ns(reg, &name)
+}
+
+// The env that eval evaluates its argument in: the current namespace,
+// or the root env when there are no namespaces
+pub fn eval_env(env: &Env) -> Env {
+    match env_ns(env).and_then(|tag| tag.registry()) {
+        Some(reg) => current_env(&reg),
+        None => env_root(env),
+    }
+}
+
+// "ns/name" split in two. "/" alone is an ordinary symbol.
+fn split_qualified(s: &str) -> Option<(&str, &str)> {
+    match s.find('/') {
+        Some(i) if i > 0 && i < s.len() - 1 => Some((&s[..i], &s[i+1..])),
+        _ => None,
+    }
+}
+
+// The namespace env a qualified symbol refers to, found through an
+// alias of the namespace env belongs to or the full namespace name,
+// along with the symbol's unqualified name
+fn qualified(env: &Env, sym: &MalVal) -> Result<Option<(Env, MalVal)>, MalError> {
+    let (ns, name) = match **sym {
+        Sym(ref s) => match split_qualified(s) {
+            Some(parts) => parts,
+            None => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+    let tag = match env_ns(env) {
+        Some(tag) => tag,
+        None => return Ok(None),
+    };
+    let reg = match tag.registry() {
+        Some(reg) => reg,
+        None => return Ok(None),
+    };
+    let reg = reg.borrow();
+    let full = reg.namespaces.get(&tag.name)
+        .and_then(|own| own.aliases.get(ns))
+        .map(|full| &full[..])
+        .unwrap_or(ns);
+    match reg.namespaces.get(full) {
+        Some(target) => Ok(Some((target.env.clone(), symbol(name)))),
+        None => Err(MalError::runtime(format!("No such namespace: {}", ns))),
+    }
+}
+
+// Look up a symbol, which may be qualified by a namespace
+pub fn resolve(env: &Env, sym: &MalVal) -> MalRet {
+    let res = env_get(env, sym);
+    if res.is_ok() {
+        return res;
+    }
+    match qualified(env, sym)? {
+        Some((target, name)) => env_get(&target, &name).map_err(|_| {
+            MalError::runtime(format!("'{}' not found", sym.pr_str(false)))
+        }),
+        None => res,
+    }
+}
+
+// The env defining the var a symbol refers to, and the symbol it is
+// defined as there
+pub fn find_var(env: &Env, sym: &MalVal) -> Result<Option<(Env, MalVal)>, MalError> {
+    if let Some(e) = env_find(env, sym) {
+        return Ok(Some((e, sym.clone())));
+    }
+    match qualified(env, sym)? {
+        Some((target, name)) => Ok(env_find(&target, &name).map(|e| (e, name))),
+        None => Ok(None),
+    }
+}
+
+// Load a file named by load-file. A relative path is taken to be
+// relative to the file being loaded, if any.
+pub fn load_file(reg: &RegistryRef, caps: &Capabilities, path: &str) -> MalRet {
+    let path = match reg.borrow().file {
+        Some(ref file) if Path::new(path).is_relative() => {
+            let dir = Path::new(file).parent().unwrap_or(Path::new(""));
+            dir.join(path).to_string_lossy().into_owned()
+        },
+        _ => path.to_string(),
+    };
+    load_path(reg, caps, &path)
+}
+
+// Evaluate each form of a file in turn in the current namespace, with
+// *file* bound to its path. Both are restored afterwards, in case the
+// file switched to another namespace. The value is that of the last
+// form, or nil if the file has none.
+fn load_path(reg: &RegistryRef, caps: &Capabilities, path: &str) -> MalRet {
+    let src = caps.read_to_string("load-file", path)?;
+    let forms = reader::read_file_all(src, path)?;
+    let saved_ns = current_ns(reg);
+    let saved_file = set_file(reg, Some(path.to_string()));
+    let res = forms.into_iter()
+        .try_fold(_nil(), |_, form| eval::eval(form, current_env(reg)));
+    set_file(reg, saved_file);
+    reg.borrow_mut().current = saved_ns;
+    res
+}
+
+// Set the file being loaded and *file*, returning the previous file
+fn set_file(reg: &RegistryRef, file: Option<String>) -> Option<String> {
+    let val = match file {
+        Some(ref f) => string(f.clone()),
+        None => _nil(),
+    };
+    if let Some(core) = ns_env(reg, CORE_NS) {
+        env_set(&core, symbol("*file*"), val);
+    }
+    mem::replace(&mut reg.borrow_mut().file, file)
+}
+
+pub fn set_search_path(reg: &RegistryRef, path: Vec<PathBuf>) {
+    reg.borrow_mut().path = path;
+}
+
+// The file a namespace is loaded from: foo.bar-baz is in foo/bar_baz.mal
+fn ns_file(name: &str) -> String {
+    format!("{}.mal", name.replace('.', "/").replace('-', "_"))
+}
+
+// The first file on the search path for namespace name. Directories
+// that caps doesn't allow reading from are skipped.
+fn find_ns_file(reg: &RegistryRef, caps: &Capabilities, name: &str) -> Result<String, MalError> {
+    let file = ns_file(name);
+    let mut denied = None;
+    for dir in reg.borrow().path.iter() {
+        let path = if dir == Path::new(".") {
+            file.clone()
+        } else {
+            dir.join(&file).to_string_lossy().into_owned()
+        };
+        if let Err(e) = caps.check_read("require", &path) {
+            denied = denied.or(Some(e));
+        } else if Path::new(&path).is_file() {
+            return Ok(path);
+        }
+    }
+    Err(denied.unwrap_or_else(|| {
+        MalError::runtime(format!("Could not locate {} as {} on the search path", name, file))
+    }))
+}
+
+// Load a namespace from its file unless it has been loaded already
+fn load_ns(reg: &RegistryRef, caps: &Capabilities, name: &str) -> Result<(), MalError> {
+    let cycle = {
+        let reg = reg.borrow();
+        if reg.loading.iter().any(|n| n == name) {
+            let mut chain = reg.loading.clone();
+            chain.push(name.to_string());
+            Some(chain.join(" -> "))
+        } else {
+            None
+        }
+    };
+    if let Some(chain) = cycle {
+        return Err(MalError::runtime(format!("Circular require: {}", chain)));
+    }
+    if ns_env(reg, name).is_some() {
+        return Ok(());
+    }
+
+    let file = find_ns_file(reg, caps, name)?;
+    reg.borrow_mut().loading.push(name.to_string());
+    let res = load_path(reg, caps, &file);
+    reg.borrow_mut().loading.pop();
+    if let Err(e) = res {
+        // Forget a partly loaded namespace so that it can be required again
+        reg.borrow_mut().namespaces.remove(name);
+        return Err(e);
+    }
+    if ns_env(reg, name).is_none() {
+        return Err(MalError::runtime(format!("require of {}: {} did not define namespace {}",
+                                             name, file, name)));
+    }
+    Ok(())
+}
+
+// Load the namespace named by spec and apply its :as and :refer options
+// to the current namespace. spec is a symbol or [name & options].
+fn require_one(reg: &RegistryRef, caps: &Capabilities, spec: &MalVal) -> Result<(), MalError> {
+    let (name, opts) = match **spec {
+        Sym(ref name) => (name.to_string(), vec![]),
+        List(ref v,_) | Vector(ref v,_) if !v.is_empty() => match *v[0] {
+            Sym(ref name) => (name.to_string(), v.iter().skip(1).cloned().collect()),
+            _ => return Err(MalError::runtime(format!("require of non-symbol {}", v[0].pr_str(true)))),
+        },
+        _ => return Err(MalError::runtime(format!("invalid require spec {}", spec.pr_str(true)))),
+    };
+    if opts.len() % 2 != 0 {
+        return Err(MalError::runtime(format!("require options of {} must be pairs", name)));
+    }
+    load_ns(reg, caps, &name)?;
+
+    let target = ns_env(reg, &name).expect("required namespace missing");
+    let current = current_ns(reg);
+    let into = current_env(reg);
+    for pair in opts.chunks(2) {
+        match (&*pair[0], &*pair[1]) {
+            (Keyword(k), Sym(alias)) if &**k == "as" => {
+                let mut reg = reg.borrow_mut();
+                if let Some(ns) = reg.namespaces.get_mut(&current) {
+                    ns.aliases.insert(alias.to_string(), name.clone());
+                }
+            },
+            (Keyword(k), Keyword(all)) if &**k == "refer" && &**all == "all" => {
+                for sym in env_symbols(&target) {
+                    let val = env_get(&target, &symbol(&sym))?;
+                    env_set(&into, symbol(&sym), val);
+                }
+            },
+            (&Keyword(ref k), &List(ref syms,_)) |
+            (&Keyword(ref k), &Vector(ref syms,_)) if &**k == "refer" => {
+                for sym in syms.iter() {
+                    let val = env_get(&target, sym).map_err(|_| {
+                        MalError::runtime(format!("{}/{} not found", name, sym.pr_str(false)))
+                    })?;
+                    env_set(&into, sym.clone(), val);
+                }
+            },
+            _ => return Err(MalError::runtime(format!("unsupported require option {} {}",
+                                                      pair[0].pr_str(true),
+                                                      pair[1].pr_str(true)))),
+        }
+    }
+    Ok(())
+}
+
+// in-ns, require and load-file, which work on the namespaces in reg,
+// and *file*.
+// They hold a weak reference, since reg owns the env they are bound in.
+pub fn builtins(reg: &RegistryRef, caps: &Capabilities) -> HashMap<String,MalVal> {
+    let mut ns = HashMap::new();
+    let caps = Rc::new(caps.clone());
+    ns.insert("*file*".to_string(), _nil());
+
+    let r = Rc::downgrade(reg);
+    ns.insert("in-ns".to_string(), native_fn("in-ns", move |a| {
+        let reg = upgrade(&r)?;
+        if a.len() != 1 {
+            return err_str("Wrong arity to in-ns call");
+        }
+        match *a[0] {
+            Sym(ref name) => { set_current_ns(&reg, name); Ok(_nil()) },
+            _ => err_str("in-ns called with non-symbol"),
+        }
+    }));
+
+    let (r, c) = (Rc::downgrade(reg), caps.clone());
+    ns.insert("require".to_string(), native_fn("require", move |a| {
+        let reg = upgrade(&r)?;
+        for spec in a.iter() {
+            require_one(&reg, &c, spec)?;
+        }
+        Ok(_nil())
+    }));
+
+    let (r, c) = (Rc::downgrade(reg), caps.clone());
+    ns.insert("load-file".to_string(), native_fn("load-file", move |a| {
+        let reg = upgrade(&r)?;
+        if a.len() != 1 {
+            return err_str("Wrong arity to load-file call");
+        }
+        match *a[0] {
+            Strn(ref path) => load_file(&reg, &c, path),
+            _ => err_str("load-file called with non-string"),
+        }
+    }));
+
+    ns
+}
+
+fn upgrade(reg: &Weak<RefCell<Registry>>) -> Result<RegistryRef, MalError> {
+    reg.upgrade().ok_or_else(|| MalError::runtime("interpreter has been dropped".to_string()))
+}
@@ -1,60 +1,127 @@
 use std::borrow::ToOwned;
-use types::MalError::{ErrString, ErrMalVal};
-use types::{MalVal, MalRet,
-            _nil, _true, _false, _int, symbol, string, list, vector, hash_mapv,
-            err_str, err_string, err_val};
+use std::f64;
+use num::{BigInt, BigRational, Zero};
+use sync::Rc;
+use types::{MalVal, MalRet, MalError, ErrorKind, Pos,
+            _nil, _true, _false, _int, _bigint, _ratio, _float, symbol, keyword, string,
+            list, listm, vectorm, hash_mapm, hash_mapv,
+            err_str};
+use types::MalType::{Keyword, Hash_Map};
 use super::printer::unescape_str;
 
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+    col:  usize,
+}
+
 #[derive(Debug, Clone)]
 struct Reader {
-    tokens: Vec<String>,
+    tokens: Vec<Token>,
     position: usize,
+    file: Option<Rc<str>>,
 }
 
 impl Reader {
     fn next(&mut self) -> Option<String> {
         if self.position < self.tokens.len() {
             self.position += 1;
-            Some(self.tokens[self.position-1].to_string())
+            Some(self.tokens[self.position-1].text.to_string())
         } else {
             None
         }
     }
     fn peek(&self) -> Option<String> {
         if self.position < self.tokens.len() {
-            Some(self.tokens[self.position].to_string())
+            Some(self.tokens[self.position].text.to_string())
         } else {
             None
         }
     }
+    // Position of the next token (or of the end of input)
+    fn pos(&self) -> Pos {
+        let (line, col) = match self.tokens.get(self.position) {
+            Some(t) => (t.line, t.col),
+            None => match self.tokens.last() {
+                Some(t) => (t.line, t.col + t.text.chars().count()),
+                None => (1, 1),
+            },
+        };
+        Pos{file: self.file.clone(), line, col}
+    }
+    fn error(&self, msg: String) -> MalError {
+        error_at(msg, self.pos())
+    }
+}
+
+fn error_at(msg: String, pos: Pos) -> MalError {
+    MalError::new(ErrorKind::Reader, string(msg)).at(pos)
 }
 
-fn tokenize(str: String) -> Vec<String> {
+fn tokenize(str: String) -> Vec<Token> {
     let mut results = vec![];
     let re = regex!(r###"[\s,]*(~@|[\[\]{}()'`~^@]|"(?:\\.|[^\\"])*"|;.*|[^\s\[\]{}('"`,;)]*)"###);
+    let (mut line, mut col, mut offset) = (1, 1, 0);
     for cap in re.captures_iter(&str) {
         let group = cap.at(1).unwrap_or("");
-        if group == "" { break; }
+        if group.is_empty() { break; }
+        let start = cap.pos(1).map(|(s,_)| s).unwrap_or(offset);
+        for c in str[offset..start].chars() {
+            if c == '\n' { line += 1; col = 1; } else { col += 1; }
+        }
+        offset = start;
         if group.starts_with(";") { continue; }
-        results.push(group.to_owned());
+        results.push(Token{text: group.to_owned(), line, col});
     }
     results
 }
 
+// only called on tokens already matched as decimal digits
+fn parse_bigint(digits: &str) -> BigInt {
+    let digits = digits.strip_prefix('+').unwrap_or(digits);
+    BigInt::parse_bytes(digits.as_bytes(), 10).unwrap()
+}
+
 fn read_atom(rdr : &mut Reader) -> MalRet {
+    let pos = rdr.pos();
     let otoken = rdr.next();
     //println!("read_atom: {}", otoken);
     if otoken.is_none() { return err_str("read_atom underflow"); }
     let stoken = otoken.unwrap();
     let token = &stoken[..];
-    if regex!(r"^-?[0-9]+$").is_match(token) {
-        let num : Option<isize> = token.parse().ok();
-        Ok(_int(num.unwrap()))
+    if regex!(r"^[-+]?[0-9]+$").is_match(token) {
+        // integer literals that don't fit in an Int become BigInts
+        match token.parse::<isize>() {
+            Ok(i) => Ok(_int(i)),
+            Err(_) => Ok(_bigint(parse_bigint(token))),
+        }
+    } else if regex!(r"^[-+]?[0-9]+N$").is_match(token) {
+        Ok(_bigint(parse_bigint(&token[..token.len()-1])))
+    } else if regex!(r"^[-+]?[0-9]+/[0-9]+$").is_match(token) {
+        let mut parts = token.splitn(2, '/');
+        let numer = parse_bigint(parts.next().unwrap());
+        let denom = parse_bigint(parts.next().unwrap());
+        if denom.is_zero() {
+            return Err(error_at(format!("divide by zero in ratio {}", token), pos));
+        }
+        Ok(_ratio(BigRational::new(numer, denom)))
+    } else if regex!(r"^[-+]?[0-9]+(\.[0-9]*)?([eE][-+]?[0-9]+)?$").is_match(token) {
+        Ok(_float(token.parse::<f64>().unwrap()))
+    } else if regex!(r"^[-+]?[0-9]").is_match(token) {
+        // a symbol can't start with a digit, so this is a malformed number
+        Err(error_at(format!("invalid number {}", token), pos))
+    } else if token == "##Inf" {
+        Ok(_float(f64::INFINITY))
+    } else if token == "##-Inf" {
+        Ok(_float(f64::NEG_INFINITY))
+    } else if token == "##NaN" {
+        Ok(_float(f64::NAN))
     } else if regex!(r#"^".*"$"#).is_match(token) {
         let new_str = &token[1..token.len()-1];
         Ok(string(unescape_str(new_str)))
     } else if regex!(r#"^:"#).is_match(token) {
-        Ok(string(format!("\u{29e}{}", &token[1..])))
+        Ok(keyword(&token[1..]))
     } else if token == "nil" {
         Ok(_nil())
     } else if token == "true" {
@@ -66,32 +133,29 @@ fn read_atom(rdr : &mut Reader) -> MalRet {
     }
 }
 
-fn read_seq(rdr : &mut Reader, start: &str, end: &str) -> Result<Vec<MalVal>,String> {
+fn read_seq(rdr : &mut Reader, start: &str, end: &str) -> Result<Vec<MalVal>,MalError> {
+    let start_pos = rdr.pos();
     let otoken = rdr.next();
     if otoken.is_none() {
-        return Err("read_atom underflow".to_string());
+        return Err(rdr.error("read_atom underflow".to_string()));
     }
     let stoken = otoken.unwrap();
     let token = &stoken[..];
     if token != start {
-        return Err(format!("expected '{}'", start))
+        return Err(error_at(format!("expected '{}'", start), start_pos))
     }
 
     let mut ast_vec : Vec<MalVal> = vec![];
     loop {
         let otoken = rdr.peek();
         if otoken.is_none() {
-            return Err(format!("expected '{}', got EOF", end));
+            return Err(error_at(format!("expected '{}', got EOF", end), start_pos));
         }
         let stoken = otoken.unwrap();
         let token = &stoken[..];
         if token == end { break; }
 
-        match read_form(rdr) {
-            Ok(mv) => ast_vec.push(mv),
-            Err(ErrString(es)) => return Err(es),
-            Err(ErrMalVal(_)) => return Err("read_seq exception".to_string()),
-        }
+        ast_vec.push(read_form(rdr)?);
     }
     rdr.next();
 
@@ -99,29 +163,33 @@ fn read_seq(rdr : &mut Reader, start: &str, end: &str) -> Result<Vec<MalVal>,Str
 }
 
 fn read_list(rdr : &mut Reader) -> MalRet {
-    match read_seq(rdr, "(", ")") {
-        Ok(seq) => Ok(list(seq)),
-        Err(es) => err_string(es),
-    }
+    let meta = rdr.pos().to_meta();
+    let seq = read_seq(rdr, "(", ")")?;
+    Ok(listm(seq, meta))
 }
 
 fn read_vector(rdr : &mut Reader) -> MalRet {
-    match read_seq(rdr, "[", "]") {
-        Ok(seq) => Ok(vector(seq)),
-        Err(es) => err_string(es),
-    }
+    let meta = rdr.pos().to_meta();
+    let seq = read_seq(rdr, "[", "]")?;
+    Ok(vectorm(seq, meta))
 }
 
 fn read_hash_map(rdr : &mut Reader) -> MalRet {
-    match read_seq(rdr, "{", "}") {
-        Ok(seq) => hash_mapv(seq),
-        Err(es) => err_string(es),
+    let pos = rdr.pos();
+    let seq = read_seq(rdr, "{", "}")?;
+    let hm = hash_mapv(seq).map_err(|e| e.at(pos.clone()))?;
+    match *hm {
+        Hash_Map(ref hm,_) => Ok(hash_mapm(hm.clone(), pos.to_meta())),
+        _ => Ok(hm.clone()),
     }
 }
 
 fn read_form(rdr : &mut Reader) -> MalRet {
     let otoken = rdr.peek();
     //println!("read_form: {}", otoken);
+    if otoken.is_none() {
+        return Err(rdr.error("expected form, got EOF".to_string()));
+    }
     let stoken = otoken.unwrap();
     let token = &stoken[..];
     match token {
@@ -157,6 +225,11 @@ fn read_form(rdr : &mut Reader) -> MalRet {
             let _ = rdr.next();
             match read_form(rdr) {
                 Ok(meta) => {
+                    // ^:kw is short for ^{:kw true}
+                    let meta = match *meta {
+                        Keyword(_) => hash_mapv(vec![meta.clone(), _true()])?,
+                        _ => meta,
+                    };
                     match read_form(rdr) {
                         Ok(f) => Ok(list(vec![symbol("with-meta"), f, meta])),
                         Err(e) => Err(e),
@@ -173,13 +246,13 @@ fn read_form(rdr : &mut Reader) -> MalRet {
             }
         },
 
-        ")" => err_str("unexected ')'"),
+        ")" => Err(rdr.error("unexected ')'".to_string())),
         "(" => read_list(rdr),
 
-        "]" => err_str("unexected ']'"),
+        "]" => Err(rdr.error("unexected ']'".to_string())),
         "[" => read_vector(rdr),
 
-        "}" => err_str("unexected '}'"),
+        "}" => Err(rdr.error("unexected '}'".to_string())),
         "{" => read_hash_map(rdr),
 
         _   => read_atom(rdr)
@@ -188,11 +261,42 @@ fn read_form(rdr : &mut Reader) -> MalRet {
 
 pub fn read_str(str :String) -> MalRet {
     let tokens = tokenize(str);
-    if tokens.len() == 0 {
-        // any malval as the error slot means empty line
-        return err_val(_nil())
+    if tokens.is_empty() {
+        return Err(MalError::new(ErrorKind::Blank, _nil()))
     }
     //println!("tokens: {}", tokens);
-    let rdr = &mut Reader{tokens: tokens, position: 0};
+    let rdr = &mut Reader{tokens, position: 0, file: None};
     read_form(rdr)
 }
+
+fn read_forms(rdr : &mut Reader) -> Result<Vec<MalVal>,MalError> {
+    let mut forms = vec![];
+    while rdr.peek().is_some() {
+        forms.push(read_form(rdr)?);
+    }
+    Ok(forms)
+}
+
+pub fn read_all(str :String) -> Result<Vec<MalVal>,MalError> {
+    let tokens = tokenize(str);
+    let rdr = &mut Reader{tokens, position: 0, file: None};
+    read_forms(rdr)
+}
+
+// Read every form in the contents of a file, tagging them with their
+// positions in the file as read_file_str does
+pub fn read_file_all(str :String, file: &str) -> Result<Vec<MalVal>,MalError> {
+    let tokens = tokenize(str);
+    let rdr = &mut Reader{tokens, position: 0,
+                          file: Some(Rc::from(file))};
+    read_forms(rdr)
+}
+
+// Read the contents of a file as a single (do ...) form, with every
+// read list, vector and hash-map tagged with its position in the file
+pub fn read_file_str(str :String, file: &str) -> MalRet {
+    let meta = Pos{file: Some(Rc::from(file)), line: 1, col: 1}.to_meta();
+    let mut forms = vec![symbol("do")];
+    forms.extend(read_file_all(str, file)?);
+    Ok(listm(forms, meta))
+}
@@ -11,7 +11,7 @@ mod ext_readline {
     extern crate libc;
     use self::libc::c_char;
     #[link(name = "readline")]
-    extern {
+    extern "C" {
         pub fn add_history(line: *const c_char);
         pub fn readline(p: *const c_char) -> *const c_char;
     }
@@ -33,20 +33,20 @@ pub fn readline(prompt: &str) -> Option<String> {
             let ret = str::from_utf8(CStr::from_ptr(ptr).to_bytes());
             let ret = ret.ok().map(|s| s.to_string());
             libc::free(ptr as *mut _);
-            return ret;
+            ret
         }
     }
 }
 
 // --------------------------------------------
 
-static mut history_loaded : bool = false;
-static HISTORY_FILE: &'static str = "/home/joelm/.mal-history";
+static mut HISTORY_LOADED : bool = false;
+static HISTORY_FILE: &str = "/home/joelm/.mal-history";
 
 fn load_history() {
     unsafe {
-        if history_loaded { return; }
-        history_loaded = true;
+        if HISTORY_LOADED { return; }
+        HISTORY_LOADED = true;
     }
 
     let file = match File::open(HISTORY_FILE) {
@@ -57,13 +57,13 @@ fn load_history() {
     for line in file.lines() {
         let rt: &[_] = &['\r', '\n'];
         let line2 = line.unwrap();
-        let line3 = line2.trim_right_matches(rt);
+        let line3 = line2.trim_end_matches(rt);
         add_history(line3);
     }
 }
 
 fn append_to_history(line: &str) {
-    let file = OpenOptions::new().append(true).write(true).create(true)
+    let file = OpenOptions::new().append(true).create(true)
                                  .open(HISTORY_FILE);
     let mut file = match file { Ok(f) => f, Err(..) => return };
     let _ = file.write_all(line.as_bytes());
@@ -0,0 +1,123 @@
+// Capabilities of the built-ins that reach outside the interpreter
+// (slurp, readline, prn, println and, through slurp, load-file), so
+// that an embedder can run untrusted code without giving it the host's
+// filesystem or terminal.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+use sync::{Rc, RefCell};
+use types::{MalError, ErrorKind, string};
+
+/// Which files `slurp` (and so `load-file`) may read.
+#[derive(Clone, Debug)]
+pub enum FileAccess {
+    All,
+    /// Only files inside this directory, after resolving symlinks
+    ReadOnlyUnder(PathBuf),
+    Denied,
+}
+
+/// Where `prn` and `println` write their output.
+#[derive(Clone, Debug)]
+pub enum Output {
+    Stdout,
+    /// Appended to a buffer that the embedder holds a clone of
+    Capture(Rc<RefCell<String>>),
+    Denied,
+}
+
+/// The I/O a root environment's built-ins are allowed to do.
+#[derive(Clone, Debug)]
+pub struct Capabilities {
+    pub files:  FileAccess,
+    pub output: Output,
+    /// Whether `readline` may read from the terminal
+    pub input:  bool,
+}
+
+impl Capabilities {
+    /// Unrestricted access, as the REPL has.
+    pub fn all() -> Capabilities {
+        Capabilities{files: FileAccess::All, output: Output::Stdout, input: true}
+    }
+
+    /// No I/O at all.
+    pub fn none() -> Capabilities {
+        Capabilities{files: FileAccess::Denied, output: Output::Denied, input: false}
+    }
+
+    pub fn check_read(&self, func: &str, path: &str) -> Result<(), MalError> {
+        match self.files {
+            FileAccess::All => Ok(()),
+            FileAccess::Denied => Err(denied(format!("{} is not permitted: file access is disabled", func))),
+            FileAccess::ReadOnlyUnder(ref dir) => {
+                if is_under(Path::new(path), dir) {
+                    Ok(())
+                } else {
+                    Err(denied(format!("{} is not permitted: {} is outside {}",
+                                       func, path, dir.display())))
+                }
+            },
+        }
+    }
+
+    /// Read the whole of a file that func is permitted to read.
+    pub fn read_to_string(&self, func: &str, path: &str) -> Result<String, MalError> {
+        self.check_read(func, path)?;
+        let mut s = String::new();
+        match File::open(path).and_then(|mut f| f.read_to_string(&mut s)) {
+            Ok(_) => Ok(s),
+            Err(e) => Err(MalError::runtime(e.to_string())),
+        }
+    }
+
+    pub fn write_line(&self, func: &str, line: &str) -> Result<(), MalError> {
+        match self.output {
+            Output::Stdout => println!("{}", line),
+            Output::Capture(ref buf) => {
+                let mut buf = buf.borrow_mut();
+                buf.push_str(line);
+                buf.push('\n');
+            },
+            Output::Denied => {
+                return Err(denied(format!("{} is not permitted: output is disabled", func)));
+            },
+        }
+        Ok(())
+    }
+
+    pub fn check_input(&self, func: &str) -> Result<(), MalError> {
+        if self.input {
+            Ok(())
+        } else {
+            Err(denied(format!("{} is not permitted: input is disabled", func)))
+        }
+    }
+}
+
+fn denied(msg: String) -> MalError {
+    MalError::new(ErrorKind::Permission, string(msg))
+}
+
+// Files that don't exist yet can't be canonicalized, so fall back to
+// comparing the path as written, provided it has no ".." in it
+fn is_under(path: &Path, dir: &Path) -> bool {
+    let dir = match fs::canonicalize(dir) {
+        Ok(dir) => dir,
+        Err(_) => return false,
+    };
+    match fs::canonicalize(path) {
+        Ok(path) => path.starts_with(&dir),
+        Err(_) => {
+            let path = match env::current_dir() {
+                Ok(cwd) => cwd.join(path),
+                Err(_) => return false,
+            };
+            !path.components().any(|c| c == Component::ParentDir) &&
+                path.starts_with(&dir)
+        },
+    }
+}
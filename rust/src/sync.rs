@@ -0,0 +1,212 @@
+// The shared pointer and interior mutability that values and envs are
+// built from. They are Rc and RefCell, unless the "threads" feature is
+// enabled, when they are Arc and a lock with the same interface as
+// RefCell, so that values and envs can be shared between threads (see
+// threads). Host code that makes values itself, such as the buffer of
+// a sandbox::Output::Capture, should use these too.
+
+#[cfg(not(feature = "threads"))]
+pub use std::rc::{Rc, Weak};
+#[cfg(not(feature = "threads"))]
+pub use std::cell::{RefCell, Ref, RefMut, BorrowError};
+
+#[cfg(feature = "threads")]
+pub use std::sync::{Arc as Rc, Weak};
+#[cfg(feature = "threads")]
+pub use self::lock::{RefCell, Ref, RefMut, BorrowError};
+
+/// What native functions and the host state they capture must be to go
+/// in a value: `Send + Sync` when values are shared between threads,
+/// and anything otherwise.
+#[cfg(not(feature = "threads"))]
+pub trait Shared {}
+#[cfg(not(feature = "threads"))]
+impl<T: ?Sized> Shared for T {}
+
+#[cfg(feature = "threads")]
+pub trait Shared: Send + Sync {}
+#[cfg(feature = "threads")]
+impl<T: ?Sized + Send + Sync> Shared for T {}
+
+#[cfg(feature = "threads")]
+mod lock {
+    use std::cell::{RefCell as LocalCell, UnsafeCell};
+    use std::fmt;
+    use std::ops::{Deref, DerefMut};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    const WRITING: usize = usize::MAX;
+
+    thread_local! {
+        // The cells the current thread has borrowed, once per borrow
+        static HELD: LocalCell<Vec<usize>> = const { LocalCell::new(Vec::new()) };
+    }
+
+    fn hold(id: usize) {
+        HELD.with(|h| h.borrow_mut().push(id));
+    }
+
+    fn release(id: usize) {
+        HELD.with(|h| {
+            let mut h = h.borrow_mut();
+            if let Some(i) = h.iter().rposition(|&held| held == id) {
+                h.swap_remove(i);
+            }
+        });
+    }
+
+    fn holds(id: usize) -> bool {
+        HELD.with(|h| h.borrow().contains(&id))
+    }
+
+    /// A reader-writer lock used like a RefCell. Any number of threads
+    /// may borrow it at once, including one that already has, which a
+    /// RefCell allows too; borrowing it mutably waits until no other
+    /// borrow is left. Borrows are expected to be brief, so waiting is
+    /// done by yielding rather than parking the thread. A thread that
+    /// would wait for a borrow of its own panics instead, as a RefCell
+    /// does, since it would wait forever.
+    ///
+    /// Waiting on another thread's borrow is only safe if the threads
+    /// take cells in the same order: two threads that each hold one
+    /// cell while waiting for the other's wait forever, which is not
+    /// detected. The interpreter holds a cell only while it reads or
+    /// writes it, never while evaluating mal code or calling a native
+    /// function (`swap!` calls its function with the atom released),
+    /// and the one time it holds two, looking a name up through an env
+    /// and its outer envs, it takes them innermost first. Host code
+    /// that borrows a value's cell must not hold it while calling into
+    /// the interpreter, nor take cells in an order another thread may
+    /// reverse.
+    pub struct RefCell<T> {
+        state: AtomicUsize,  // WRITING, or the number of borrows
+        value: UnsafeCell<T>,
+    }
+
+    unsafe impl<T: Send> Send for RefCell<T> {}
+    unsafe impl<T: Send + Sync> Sync for RefCell<T> {}
+
+    #[derive(Debug)]
+    pub struct BorrowError;
+
+    pub struct Ref<'a, T: 'a> {
+        cell: &'a RefCell<T>,
+    }
+
+    pub struct RefMut<'a, T: 'a> {
+        cell: &'a RefCell<T>,
+    }
+
+    impl<T> RefCell<T> {
+        pub fn new(value: T) -> RefCell<T> {
+            RefCell{state: AtomicUsize::new(0), value: UnsafeCell::new(value)}
+        }
+
+        fn id(&self) -> usize {
+            self as *const RefCell<T> as usize
+        }
+
+        /// Borrow the value, unless it is borrowed mutably.
+        pub fn try_borrow(&self) -> Result<Ref<'_, T>, BorrowError> {
+            let mut state = self.state.load(Ordering::Relaxed);
+            loop {
+                if state == WRITING || state == WRITING - 1 {
+                    return Err(BorrowError);
+                }
+                match self.state.compare_exchange_weak(state, state + 1,
+                                                       Ordering::Acquire, Ordering::Relaxed) {
+                    Ok(_) => {
+                        hold(self.id());
+                        return Ok(Ref{cell: self});
+                    },
+                    Err(now) => state = now,
+                }
+            }
+        }
+
+        pub fn borrow(&self) -> Ref<'_, T> {
+            loop {
+                if let Ok(r) = self.try_borrow() {
+                    return r;
+                }
+                if holds(self.id()) {
+                    panic!("already mutably borrowed");
+                }
+                thread::yield_now();
+            }
+        }
+
+        /// Borrow the value mutably, unless it is borrowed at all.
+        pub fn try_borrow_mut(&self) -> Result<RefMut<'_, T>, BorrowError> {
+            match self.state.compare_exchange(0, WRITING, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => {
+                    hold(self.id());
+                    Ok(RefMut{cell: self})
+                },
+                Err(_) => Err(BorrowError),
+            }
+        }
+
+        pub fn borrow_mut(&self) -> RefMut<'_, T> {
+            loop {
+                if let Ok(r) = self.try_borrow_mut() {
+                    return r;
+                }
+                if holds(self.id()) {
+                    panic!("already borrowed");
+                }
+                thread::yield_now();
+            }
+        }
+    }
+
+    impl<T: Clone> Clone for RefCell<T> {
+        fn clone(&self) -> RefCell<T> {
+            RefCell::new(self.borrow().clone())
+        }
+    }
+
+    impl<T: fmt::Debug> fmt::Debug for RefCell<T> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self.try_borrow() {
+                Ok(v) => write!(f, "RefCell {{ value: {:?} }}", *v),
+                Err(_) => write!(f, "RefCell {{ value: <borrowed> }}"),
+            }
+        }
+    }
+
+    impl<'a, T> Deref for Ref<'a, T> {
+        type Target = T;
+        fn deref(&self) -> &T {
+            unsafe { &*self.cell.value.get() }
+        }
+    }
+
+    impl<'a, T> Drop for Ref<'a, T> {
+        fn drop(&mut self) {
+            release(self.cell.id());
+            self.cell.state.fetch_sub(1, Ordering::Release);
+        }
+    }
+
+    impl<'a, T> Deref for RefMut<'a, T> {
+        type Target = T;
+        fn deref(&self) -> &T {
+            unsafe { &*self.cell.value.get() }
+        }
+    }
+
+    impl<'a, T> DerefMut for RefMut<'a, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            unsafe { &mut *self.cell.value.get() }
+        }
+    }
+
+    impl<'a, T> Drop for RefMut<'a, T> {
+        fn drop(&mut self) {
+            release(self.cell.id());
+            self.cell.state.store(0, Ordering::Release);
+        }
+    }
+}
@@ -0,0 +1,351 @@
+// Futures, promises and pmap, which run mal functions on a pool of
+// worker threads. Only built with the "threads" feature, which makes
+// values and envs shareable between threads (see sync).
+//
+// A future's function is queued for the pool when the future is made.
+// Dereferencing a future that no worker has started yet runs it on the
+// dereferencing thread rather than waiting for it, so futures that
+// wait on other futures can't tie up every worker waiting on work that
+// is queued behind them. A deref with a timeout only ever waits, since
+// running the function could take longer than the timeout.
+//
+// Evaluation depth, eval mode, the execution budget and dynamic
+// bindings belong to a thread (see eval, budget and env), so a future
+// doesn't see the bindings of the thread that made it or of one that
+// runs it on dereferencing it. A future is run in the eval mode of
+// the thread that made it. One made under an execution budget is run
+// there and then, against the budget, since the budget would not apply
+// on a worker.
+
+use std::collections::VecDeque;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Condvar, Mutex, MutexGuard, OnceLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use sync::Rc;
+use types::{MalVal, MalRet, MalError, err_str, _nil, _true, _false, list, native_fn};
+use types::MalType::{Int, List, Vector, Func, MalFunc, Promise as PromiseVal};
+use env::{env_unbound, Env};
+use eval::{self, Mode};
+use interpreter::panic_message;
+use budget;
+
+// Native stack to reserve for each worker per level of its depth
+// limit, as the stepA REPL reserves it
+const STACK_PER_DEPTH: usize = 32 * 1024;
+
+/// The size of the worker pool futures run on. Each worker reserves
+/// native stack for `max_depth` levels of evaluation (32KB a level), so
+/// the default of `eval::DEFAULT_MAX_DEPTH` reserves about 320MB of
+/// address space per worker.
+#[derive(Debug, Clone, Copy)]
+pub struct PoolConfig {
+    /// Worker threads; the default is the available parallelism
+    pub workers:   usize,
+    /// How deeply calls may nest in a future run by a worker
+    pub max_depth: usize,
+}
+
+impl Default for PoolConfig {
+    fn default() -> PoolConfig {
+        PoolConfig{workers: thread::available_parallelism().map(|n| n.get()).unwrap_or(4),
+                   max_depth: eval::DEFAULT_MAX_DEPTH}
+    }
+}
+
+static CONFIG: OnceLock<PoolConfig> = OnceLock::new();
+
+/// Set the size of the worker pool, which must be done before the
+/// first future is made. Returns false, changing nothing, if the pool
+/// was already configured or has started.
+pub fn configure_pool(config: PoolConfig) -> bool {
+    CONFIG.set(config).is_ok()
+}
+
+// Values and envs have to be shareable for futures to hand them over
+#[allow(dead_code)]
+fn assert_shared() {
+    fn shared<T: Send + Sync>() {}
+    shared::<MalVal>();
+    shared::<Env>();
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Future,
+    Promise,
+}
+
+// A function a future runs, and the eval mode to run it in
+struct Task {
+    f:    MalVal,
+    mode: Mode,
+}
+
+enum State {
+    Queued(Task),  // a future that no thread has started
+    Running,       // a future being run
+    Pending,       // a promise that hasn't been delivered
+    Done(MalRet),
+}
+
+/// The value of a future or promise, once some thread has delivered it.
+pub struct Promise {
+    kind:  Kind,
+    state: Mutex<State>,
+    done:  Condvar,
+}
+
+impl Promise {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn deliver(&self, res: MalRet) {
+        *self.lock() = State::Done(res);
+        self.done.notify_all();
+    }
+
+    // Run the task of a future unless some thread already has
+    fn run(&self) {
+        let task = {
+            let mut state = self.lock();
+            match *state {
+                State::Queued(_) => (),
+                _ => return,
+            }
+            match mem::replace(&mut *state, State::Running) {
+                State::Queued(task) => task,
+                _ => unreachable!(),
+            }
+        };
+        let mode = eval::mode();
+        eval::set_mode(task.mode);
+        let run = || panic::catch_unwind(AssertUnwindSafe(|| task.f.apply(vec![])));
+        let res = match env_unbound(run) {
+            Ok(res) => res,
+            Err(cause) => Err(MalError::runtime(format!("internal error: {}",
+                                                        panic_message(&cause)))),
+        };
+        eval::set_mode(mode);
+        self.deliver(res);
+        pool().active.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// The delivered value, or the error the future raised, waiting for
+    /// at most `timeout` for it if it hasn't been delivered yet. Without
+    /// a timeout, a future no thread has started is run on this one.
+    pub fn wait(&self, timeout: Option<Duration>) -> Option<MalRet> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut state = self.lock();
+        loop {
+            match *state {
+                State::Done(ref res) => return Some(res.clone()),
+                State::Queued(_) if timeout.is_none() => {
+                    drop(state);
+                    self.run();
+                    state = self.lock();
+                    continue;
+                },
+                State::Queued(_) | State::Running | State::Pending => (),
+            }
+            state = match deadline {
+                None => self.done.wait(state).unwrap_or_else(|e| e.into_inner()),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return None;
+                    }
+                    self.done.wait_timeout(state, deadline - now)
+                        .unwrap_or_else(|e| e.into_inner()).0
+                },
+            };
+        }
+    }
+
+    pub fn is_realized(&self) -> bool {
+        matches!(*self.lock(), State::Done(_))
+    }
+
+    pub fn pr_str(&self, print_readably: bool) -> String {
+        let kind = match self.kind {
+            Kind::Future => "future",
+            Kind::Promise => "promise",
+        };
+        match *self.lock() {
+            State::Done(Ok(ref v)) => format!("#<{} {}>", kind, v.pr_str(print_readably)),
+            State::Done(Err(_)) => format!("#<{} failed>", kind),
+            _ => format!("#<{} pending>", kind),
+        }
+    }
+}
+
+struct Pool {
+    queue:  Mutex<VecDeque<Rc<Promise>>>,
+    work:   Condvar,
+    active: AtomicUsize,  // futures queued or being run
+}
+
+static POOL: OnceLock<Pool> = OnceLock::new();
+
+// The pool, starting its workers the first time it is needed
+fn pool() -> &'static Pool {
+    POOL.get_or_init(|| {
+        let config = *CONFIG.get_or_init(PoolConfig::default);
+        let stack_size = config.max_depth.saturating_mul(STACK_PER_DEPTH).saturating_add(1 << 20);
+        for i in 0..config.workers.max(1) {
+            thread::Builder::new()
+                .name(format!("mal-worker-{}", i))
+                .stack_size(stack_size)
+                .spawn(move || work(config.max_depth))
+                .expect("cannot start worker thread");
+        }
+        Pool{queue: Mutex::new(VecDeque::new()), work: Condvar::new(),
+             active: AtomicUsize::new(0)}
+    })
+}
+
+fn work(max_depth: usize) {
+    eval::set_max_depth(max_depth);
+    let pool = pool();
+    loop {
+        let promise = {
+            let mut queue = pool.queue.lock().unwrap_or_else(|e| e.into_inner());
+            loop {
+                match queue.pop_front() {
+                    Some(promise) => break promise,
+                    None => queue = pool.work.wait(queue).unwrap_or_else(|e| e.into_inner()),
+                }
+            }
+        };
+        promise.run();
+    }
+}
+
+/// Whether any future is queued or being run, when values may be in
+/// use on other threads.
+pub fn busy() -> bool {
+    POOL.get().is_some_and(|pool| pool.active.load(Ordering::SeqCst) > 0)
+}
+
+// A future that calls f, queued for the pool or, under a budget, run now
+fn spawn(f: MalVal) -> Rc<Promise> {
+    let task = Task{f, mode: eval::mode()};
+    let promise = Rc::new(Promise{kind: Kind::Future, state: Mutex::new(State::Queued(task)),
+                                  done: Condvar::new()});
+    let pool = pool();
+    pool.active.fetch_add(1, Ordering::SeqCst);
+    if budget::is_set() {
+        promise.run();
+    } else {
+        pool.queue.lock().unwrap_or_else(|e| e.into_inner()).push_back(promise.clone());
+        pool.work.notify_one();
+    }
+    promise
+}
+
+fn is_fn(f: &MalVal) -> bool {
+    matches!(**f, Func(_,_) | MalFunc(_,_))
+}
+
+pub fn future_call(a: Vec<MalVal>) -> MalRet {
+    if a.len() != 1 {
+        return err_str("Wrong arity to future-call call");
+    }
+    if !is_fn(&a[0]) {
+        return err_str("future-call called with non-function");
+    }
+    Ok(Rc::new(PromiseVal(spawn(a[0].clone()))))
+}
+
+pub fn promise(a: Vec<MalVal>) -> MalRet {
+    if !a.is_empty() {
+        return err_str("Wrong arity to promise call");
+    }
+    Ok(Rc::new(PromiseVal(Rc::new(Promise{kind: Kind::Promise,
+                                          state: Mutex::new(State::Pending),
+                                          done: Condvar::new()}))))
+}
+
+// Delivers a promise the first time; after that it returns nil
+pub fn deliver(a: Vec<MalVal>) -> MalRet {
+    if a.len() != 2 {
+        return err_str("Wrong arity to deliver call");
+    }
+    match *a[0] {
+        PromiseVal(ref p) if p.kind == Kind::Promise => {
+            {
+                let mut state = p.lock();
+                match *state {
+                    State::Pending => *state = State::Done(Ok(a[1].clone())),
+                    _ => return Ok(_nil()),
+                }
+            }
+            p.done.notify_all();
+            Ok(a[0].clone())
+        },
+        _ => err_str("deliver called on non-promise"),
+    }
+}
+
+// (deref p) or (deref p timeout-ms timeout-val)
+pub fn deref(p: &Promise, a: &[MalVal]) -> MalRet {
+    let (timeout, timeout_val) = match a.len() {
+        1 => (None, _nil()),
+        3 => match *a[1] {
+            Int(ms) if ms >= 0 => (Some(Duration::from_millis(ms as u64)), a[2].clone()),
+            _ => return err_str("deref timeout must be a non-negative integer"),
+        },
+        _ => return err_str("Wrong arity to deref call"),
+    };
+    match p.wait(timeout) {
+        Some(res) => res,
+        None => Ok(timeout_val),
+    }
+}
+
+pub fn realized_q(a: Vec<MalVal>) -> MalRet {
+    if a.len() != 1 {
+        return err_str("Wrong arity to realized? call");
+    }
+    match *a[0] {
+        PromiseVal(ref p) => Ok(if p.is_realized() { _true() } else { _false() }),
+        _ => err_str("realized? called on non-future"),
+    }
+}
+
+pub fn future_q(a: Vec<MalVal>) -> MalRet {
+    if a.len() != 1 {
+        return err_str("Wrong arity to future? call");
+    }
+    match *a[0] {
+        PromiseVal(ref p) if p.kind == Kind::Future => Ok(_true()),
+        _ => Ok(_false()),
+    }
+}
+
+// Like map, but calling f on each element in a future of its own
+pub fn pmap(a: Vec<MalVal>) -> MalRet {
+    if a.len() != 2 {
+        return err_str("Wrong arity to pmap call");
+    }
+    if !is_fn(&a[0]) {
+        return err_str("pmap called with non-function");
+    }
+    let items = match *a[1] {
+        List(ref v,_) | Vector(ref v,_) => v,
+        _ => return err_str("pmap call with non-sequence"),
+    };
+    let futures: Vec<Rc<Promise>> = items.iter().map(|item| {
+        let (f, item) = (a[0].clone(), item.clone());
+        spawn(native_fn("", move |_| f.apply(vec![item.clone()])))
+    }).collect();
+    let mut results = vec![];
+    for future in futures.iter() {
+        results.push(future.wait(None).expect("wait without a timeout timed out")?);
+    }
+    Ok(list(results))
+}
@@ -1,14 +1,28 @@
 #![allow(dead_code)]
 
-use std::rc::Rc;
-use std::cell::RefCell;
+use std::cell;
 use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher, BuildHasherDefault};
 use std::fmt;
+#[cfg(feature = "threads")]
+use std::sync::{LazyLock, Mutex};
+use num;
+#[cfg(not(feature = "threads"))]
+use im_rc as im;
+use sync::{Rc, RefCell, Shared};
+use num::traits::ToPrimitive;
 use super::printer::{escape_str,pr_list};
-use super::env::{Env,env_new,env_bind};
+use super::env::{Env,env_new,env_bind,env_bind_recur,param_arity,arity_str};
+use super::eval::check_depth;
+use super::compiler::Proto;
+use super::analyzer::Expansion;
+use super::gc;
+use super::budget;
+#[cfg(feature = "threads")]
+use super::threads;
 
 use self::MalType::*;
-use self::MalError::*;
 
 #[derive(Clone)]
 #[allow(non_camel_case_types)]
@@ -17,46 +31,303 @@ pub enum MalType {
     True,
     False,
     Int(isize),
-    Strn(String),
-    Sym(String),
-    List(Vec<MalVal>, MalVal),
-    Vector(Vec<MalVal>, MalVal),
-    Hash_Map(HashMap<String, MalVal>, MalVal),
-    Func(fn(Vec<MalVal>) -> MalRet, MalVal),
+    BigInt(num::BigInt),
+    Ratio(num::BigRational),
+    Float(f64),
+    Strn(Rc<str>),
+    Sym(Symbol),
+    Keyword(Rc<str>),
+    List(MalVec, MalVal),
+    Vector(MalVec, MalVal),
+    Hash_Map(MalMap, MalVal),
+    Func(FuncData, MalVal),
     MalFunc(MalFuncData, MalVal),
     Atom(RefCell<MalVal>),
+    ExInfo(String, MalVal, MalVal),  // message, data map, cause
+    // A symbol in an analyzed fn* body that names a local: the binding
+    // in slot index of the frame depth levels out (see analyzer)
+    Local(Symbol, usize, usize),
+    // The compiled body of a fn* made by the VM, in place of the body
+    // form in its MalFunc (see compiler)
+    Code(Rc<Proto>),
+    // A macro call in an analyzed fn* body, with its expansion made
+    // when the fn* was evaluated (see analyzer)
+    Expanded(Rc<Expansion>),
+    // A future or promise, whose value is delivered by another thread
+    // (see threads)
+    #[cfg(feature = "threads")]
+    Promise(Rc<threads::Promise>),
 }
 
 pub type MalVal = Rc<MalType>;
 
-#[derive(Debug)]
-pub enum MalError {
-    ErrString(String),
-    ErrMalVal(MalVal),
+// Persistent (structurally shared) backing stores for lists, vectors
+// and hash-maps, so that cons/rest/conj/assoc don't copy the collection
+pub type MalVec = im::Vector<MalVal>;
+pub type MalMap = im::HashMap<MalVal, MalVal>;
+
+// What raised an error, so host code doesn't have to parse messages
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    Blank,    // the reader found no form (empty or comment-only input)
+    Reader,   // malformed source text
+    Runtime,  // raised by the evaluator or a built-in function
+    Thrown,   // value thrown from mal code with throw
+    Recur,    // arguments of a recur, on their way back to apply
+    Budget,   // the execution budget ran out; not caught by try*
+    Permission, // a built-in was denied a capability (see sandbox)
+}
+
+// A function that was being called when an error was raised
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub name: String,
+    pub pos:  Option<Pos>,  // where the function was called from
+}
+
+#[derive(Debug, Clone)]
+pub struct MalError {
+    pub kind:  ErrorKind,
+    pub value: MalVal,      // the message string, or the thrown value
+    pub pos:   Option<Pos>, // innermost form with a known position
+    pub stack: Vec<Frame>,  // mal call stack, innermost call first
+}
+
+impl MalError {
+    pub fn new(kind: ErrorKind, value: MalVal) -> MalError {
+        MalError{kind, value, pos: None, stack: vec![]}
+    }
+
+    pub fn runtime(msg: String) -> MalError {
+        MalError::new(ErrorKind::Runtime, string(msg))
+    }
+
+    // Attach a source position unless a more specific one is already set
+    pub fn at(mut self, pos: Pos) -> MalError {
+        if self.pos.is_none() {
+            self.pos = Some(pos);
+        }
+        self
+    }
+
+    // Record that the error passed out of a call to the named function
+    pub fn in_frame(mut self, name: &str, pos: Option<Pos>) -> MalError {
+        self.stack.push(Frame{name: name.to_string(), pos});
+        self
+    }
+
+    pub fn message(&self) -> String {
+        if self.kind == ErrorKind::Recur {
+            return "Can only recur from tail position".to_string();
+        }
+        match *self.value {
+            ExInfo(ref msg,_,_) => msg.clone(),
+            Strn(ref s) if self.kind != ErrorKind::Thrown => s.to_string(),
+            _ => self.value.pr_str(true),
+        }
+    }
+
+    pub fn data(&self) -> Option<MalVal> {
+        match *self.value {
+            ExInfo(_,ref data,_) => Some(data.clone()),
+            _ => None,
+        }
+    }
+
+    // The value a catch* clause binds: thrown values as they were
+    // thrown, anything else as its message string
+    pub fn to_val(&self) -> MalVal {
+        match self.kind {
+            ErrorKind::Thrown => self.value.clone(),
+            _ => string(self.message()),
+        }
+    }
+}
+
+const MAX_SHOWN_FRAMES: usize = 20;
+
+impl fmt::Display for MalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (write!(f, "{}", self.message()))?;
+        if let Some(data) = self.data() {
+            (write!(f, " {}", data.pr_str(true)))?;
+        }
+        if let Some(ref pos) = self.pos {
+            (write!(f, " (at {})", pos))?;
+        }
+        // deep recursion leaves thousands of frames, so only show the
+        // innermost ones
+        for frame in self.stack.iter().take(MAX_SHOWN_FRAMES) {
+            let name = if frame.name.is_empty() { "fn*" } else { &frame.name[..] };
+            (match frame.pos {
+                Some(ref pos) => write!(f, "\n  in {}, called at {}", name, pos),
+                None => write!(f, "\n  in {}", name),
+            })?;
+        }
+        if self.stack.len() > MAX_SHOWN_FRAMES {
+            (write!(f, "\n  ... {} more", self.stack.len() - MAX_SHOWN_FRAMES))?;
+        }
+        Ok(())
+    }
 }
 
 pub type MalRet = Result<MalVal,MalError>;
 
 
 pub fn err_string(s: String) -> MalRet {
-    Err(ErrString(s))
+    Err(MalError::runtime(s))
 }
 
 pub fn err_str(s: &str) -> MalRet {
-    Err(ErrString(s.to_string()))
+    Err(MalError::runtime(s.to_string()))
 }
 
 pub fn err_val(mv: MalVal) -> MalRet {
-    Err(ErrMalVal(mv))
+    Err(MalError::new(ErrorKind::Thrown, mv))
+}
+
+// Source position of a form, as recorded by the reader
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pos {
+    pub file: Option<Rc<str>>,
+    pub line: usize,
+    pub col:  usize,
+}
+
+impl Pos {
+    pub fn to_meta(&self) -> MalVal {
+        let mut hm = MalMap::new();
+        if let Some(ref file) = self.file {
+            hm.insert(keyword("file"), strn(file));
+        }
+        hm.insert(keyword("line"), _int(self.line as isize));
+        hm.insert(keyword("column"), _int(self.col as isize));
+        hash_map(hm)
+    }
+
+    pub fn from_meta(meta: &MalVal) -> Option<Pos> {
+        let hm = match **meta {
+            Hash_Map(ref hm,_) => hm,
+            _ => return None,
+        };
+        let line = match hm.get(&keyword("line")).map(|v| &**v) {
+            Some(&Int(l)) => l as usize,
+            _ => return None,
+        };
+        let col = match hm.get(&keyword("column")).map(|v| &**v) {
+            Some(&Int(c)) => c as usize,
+            _ => return None,
+        };
+        let file = match hm.get(&keyword("file")).map(|v| &**v) {
+            Some(Strn(f)) => Some(f.clone()),
+            _ => None,
+        };
+        Some(Pos{file, line, col})
+    }
 }
 
+impl fmt::Display for Pos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.file {
+            Some(ref file) => write!(f, "{}:{}:{}", file, self.line, self.col),
+            None => write!(f, "{}:{}", self.line, self.col),
+        }
+    }
+}
+
+// Position of a form read by the reader, if it has one
+pub fn form_pos(form: &MalVal) -> Option<Pos> {
+    match **form {
+        List(_,ref meta) |
+        Vector(_,ref meta) |
+        Hash_Map(_,ref meta) => Pos::from_meta(meta),
+        _ => None,
+    }
+}
+
+// Native (rust) function, possibly a closure capturing host state
+#[derive(Clone)]
+pub struct FuncData {
+    pub name: String,
+    pub f:    Rc<dyn NativeFn>,
+}
+
+pub trait NativeFn: Fn(Vec<MalVal>) -> MalRet + Shared {}
+impl<F: Fn(Vec<MalVal>) -> MalRet + Shared> NativeFn for F {}
+
 #[derive(Clone)]
 pub struct MalFuncData {
+    pub name:     String,
     pub eval:     fn(MalVal, Env) -> MalRet,
     pub exp:      MalVal,
     pub env:      Env,
     pub params:   MalVal,
     pub is_macro: bool,
+    // (params, exp) of each clause of a multi-arity fn*, in which case
+    // params and exp above are those of the first clause
+    pub arities:  Vec<(MalVal, MalVal)>,
+}
+
+impl MalFuncData {
+    // The parameters and body to call with nargs arguments
+    pub fn arity(&self, nargs: usize) -> Result<(MalVal, MalVal), MalError> {
+        let accepts = |params: &MalVal| match param_arity(params) {
+            (fixed, false) => nargs == fixed,
+            (fixed, true) => nargs >= fixed,
+        };
+        if self.arities.is_empty() {
+            if accepts(&self.params) {
+                return Ok((self.params.clone(), self.exp.clone()));
+            }
+        } else {
+            // a fixed arity takes precedence over the variadic one
+            for &variadic in [false, true].iter() {
+                for (params, exp) in self.arities.iter() {
+                    if param_arity(params).1 == variadic && accepts(params) {
+                        return Ok((params.clone(), exp.clone()));
+                    }
+                }
+            }
+        }
+        let expected = if self.arities.is_empty() {
+            arity_str(&self.params)
+        } else {
+            expected_arities(&self.arities)
+        };
+        let name = if self.name.is_empty() { "fn*" } else { &self.name[..] };
+        Err(MalError::runtime(format!("Wrong number of args ({}) passed to {}, expected {}",
+                                      nargs, name, expected)))
+    }
+}
+
+// The counts of args a multi-arity fn takes, in order, with the fixed
+// counts just below the variadic one merged into it: ([a] [a b] [a b & c])
+// expects "1 or more"
+fn expected_arities(arities: &[(MalVal, MalVal)]) -> String {
+    let mut fixed: Vec<usize> = vec![];
+    let mut variadic = None;
+    for (params,_) in arities.iter() {
+        match param_arity(params) {
+            (n, false) => fixed.push(n),
+            (n, true) => variadic = Some(n),
+        }
+    }
+    fixed.sort();
+    let mut counts: Vec<String> = vec![];
+    if let Some(mut min) = variadic {
+        while min > 0 && fixed.contains(&(min - 1)) {
+            min -= 1;
+        }
+        fixed.retain(|&n| n < min);
+        counts.extend(fixed.iter().map(|n| n.to_string()));
+        counts.push(format!("{} or more", min));
+    } else {
+        counts.extend(fixed.iter().map(|n| n.to_string()));
+    }
+    match counts.split_last() {
+        Some((last, rest)) if !rest.is_empty() => format!("{} or {}", rest.join(", "), last),
+        _ => counts.join(""),
+    }
 }
 
 impl MalType {
@@ -67,16 +338,28 @@ impl MalType {
             True => "true".to_string(),
             False => "false".to_string(),
             Int(v) => v.to_string(),
-            Sym(ref v) => v.clone(),
+            BigInt(ref v) => format!("{}N", v),
+            Ratio(ref v) => format!("{}/{}", v.numer(), v.denom()),
+            Float(v) => {
+                if v.is_nan() {
+                    "##NaN".to_string()
+                } else if v.is_infinite() {
+                    if v > 0.0 { "##Inf".to_string() } else { "##-Inf".to_string() }
+                } else {
+                    format!("{:?}", v)
+                }
+            },
+            Sym(ref v) | Local(ref v,_,_) => v.to_string(),
+            Code(ref proto) => proto.source.pr_str(print_readably),
+            Expanded(ref site) => site.form.pr_str(print_readably),
             Strn(ref v) => {
-                if v.starts_with("\u{29e}") {
-                    format!(":{}", &v[2..])
-                } else if print_readably {
+                if print_readably {
                     escape_str(v)
                 } else {
-                    v.clone()
+                    v.to_string()
                 }
             },
+            Keyword(ref v) => format!(":{}", v),
             List(ref v,_) => {
                 pr_list(v, _r, "(", ")", " ")
             },
@@ -85,41 +368,78 @@ impl MalType {
             },
             Hash_Map(ref v,_) => {
                 let mut res = String::new();
-                res.push_str("{");
+                res.push('{');
                 for (i, (key, value)) in v.iter().enumerate() {
-                    if i != 0 { res.push_str(" "); }
-                    if key.starts_with("\u{29e}") {
-                        res.push_str(":");
-                        res.push_str(&key[2..])
-                    } else if print_readably {
-                        res.push_str(&escape_str(key))
-                    } else {
-                        res.push_str(key)
-                    }
-                    res.push_str(" ");
+                    if i != 0 { res.push(' '); }
+                    res.push_str(&key.pr_str(_r));
+                    res.push(' ');
                     res.push_str(&value.pr_str(_r));
                 }
-                res.push_str("}");
+                res.push('}');
                 res
             },
             // TODO: better native function representation
-            Func(_, _) => format!("#<function ...>"),
-            MalFunc(ref mf,_) => format!("(fn* {:?} {:?})", mf.params, mf.exp),
+            Func(ref fd, _) => {
+                if fd.name.is_empty() {
+                    "#<function ...>".to_string()
+                } else {
+                    format!("#<function {}>", fd.name)
+                }
+            },
+            MalFunc(ref mf,_) => {
+                if mf.arities.is_empty() {
+                    format!("(fn* {:?} {:?})", mf.params, mf.exp)
+                } else {
+                    let clauses: Vec<String> = mf.arities.iter()
+                        .map(|(params, exp)| format!("({:?} {:?})", params, exp))
+                        .collect();
+                    format!("(fn* {})", clauses.join(" "))
+                }
+            },
             Atom(ref v) => format!("(atom {:?})", &**v.borrow()),
+            #[cfg(feature = "threads")]
+            Promise(ref p) => p.pr_str(print_readably),
+            ExInfo(ref msg, ref data,_) => {
+                format!("#<ex-info {} {}>", escape_str(msg), data.pr_str(true))
+            },
         }
     }
 
     pub fn apply(&self, args:Vec<MalVal>) -> MalRet {
+        self.apply_at(args, None)
+    }
+
+    // Call the function, which is being called from the form at pos
+    pub fn apply_at(&self, args:Vec<MalVal>, pos: Option<Pos>) -> MalRet {
         match *self {
-            Func(f,_) => f(args),
+            Func(ref fd,_) => budget::charged(&*fd.f, args).map_err(|e| e.in_frame(&fd.name, pos)),
             MalFunc(ref mf,_) => {
                 let mfc = mf.clone();
+                let arity = check_depth(&mfc.name).and_then(|_| mfc.arity(args.len()));
+                let (params, exp) = match arity {
+                    Ok(arity) => arity,
+                    Err(e) => return Err(e.in_frame(&mf.name, pos)),
+                };
                 let alst = list(args);
                 let new_env = env_new(Some(mfc.env.clone()));
-                match env_bind(&new_env, mfc.params, alst) {
-                    Ok(_) => (mfc.eval)(mfc.exp, new_env),
+                let mut res = match env_bind(&new_env, params.clone(), alst) {
+                    Ok(_) => (mfc.eval)(exp.clone(), new_env),
                     Err(e) => err_string(e),
+                };
+                // a recur in tail position of the body comes back here
+                // with its arguments, to be rebound for another pass
+                loop {
+                    let args = match res {
+                        Err(ref e) if e.kind == ErrorKind::Recur => e.value.clone(),
+                        _ => break,
+                    };
+                    let new_env = env_new(Some(mfc.env.clone()));
+                    res = match env_bind_recur(&new_env, params.clone(), args) {
+                        Ok(_) => (mfc.eval)(exp.clone(), new_env),
+                        Err(e) => err_string(e),
+                    };
                 }
+                res.map_err(|e| e.in_frame(&mf.name, pos))
             },
             _ => err_str("attempt to call non-function"),
         }
@@ -133,18 +453,119 @@ impl PartialEq for MalType {
             (&Nil, &Nil) |
             (&True, &True) |
             (&False, &False) => true,
-            (&Int(ref a), &Int(ref b)) => a == b,
-            (&Strn(ref a), &Strn(ref b)) => a == b,
-            (&Sym(ref a), &Sym(ref b)) => a == b,
+            (Int(a), Int(b)) => a == b,
+            (&Int(a), &BigInt(ref b)) |
+            (&BigInt(ref b), &Int(a)) => num::BigInt::from(a) == *b,
+            (BigInt(a), BigInt(b)) => a == b,
+            (Ratio(a), Ratio(b)) => a == b,
+            (&Float(a), &Float(b)) => float_bits(a) == float_bits(b),
+            (Strn(a), Strn(b)) => a == b,
+            (Sym(a), Sym(b)) => a == b,
+            (&Local(ref a, ad, ai), &Local(ref b, bd, bi)) => a == b && ad == bd && ai == bi,
+            (Code(a), Code(b)) => Rc::ptr_eq(a, b),
+            (Expanded(a), Expanded(b)) => Rc::ptr_eq(a, b),
+            #[cfg(feature = "threads")]
+            (Promise(a), Promise(b)) => Rc::ptr_eq(a, b),
+            (Keyword(a), Keyword(b)) => a == b,
             (&List(ref a,_), &List(ref b,_)) |
             (&Vector(ref a,_), &Vector(ref b,_)) |
             (&List(ref a,_), &Vector(ref b,_)) |
             (&Vector(ref a,_), &List(ref b,_)) => a == b,
-            (&Hash_Map(ref a,_), &Hash_Map(ref b,_)) => a == b,
-            // TODO: fix this
-            (&Func(_,_), &Func(_,_)) => false,
-            (&MalFunc(_,_), &MalFunc(_,_)) => false,
-            _ => return false,
+            (Hash_Map(a,_), Hash_Map(b,_)) => a == b,
+            // functions are only equal to themselves
+            (Func(a,_), Func(b,_)) => Rc::ptr_eq(&a.f, &b.f),
+            (MalFunc(a,_), MalFunc(b,_)) => {
+                Rc::ptr_eq(&a.exp, &b.exp) &&
+                    Rc::ptr_eq(&a.params, &b.params) &&
+                    Rc::ptr_eq(&a.env, &b.env) &&
+                    a.is_macro == b.is_macro
+            },
+            (&Atom(_), &Atom(_)) => std::ptr::eq(self, other),
+            (ExInfo(am, ad, ac), ExInfo(bm, bd, bc)) => {
+                am == bm && ad == bd && ac == bc
+            },
+            _ => false,
+        }
+    }
+}
+
+impl Eq for MalType {}
+
+// Floats are = when their bits are, once 0.0 and -0.0 are taken to be
+// the same and every NaN to be the same, so that = is reflexive and
+// agrees with Hash. Ordering comparisons still treat NaN as unordered.
+fn float_bits(f: f64) -> u64 {
+    if f == 0.0 {
+        0
+    } else if f.is_nan() {
+        f64::NAN.to_bits()
+    } else {
+        f.to_bits()
+    }
+}
+
+// Must agree with PartialEq: lists and vectors with the same elements
+// hash the same, and hash-maps hash independently of iteration order.
+impl Hash for MalType {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match *self {
+            Nil => 0u8.hash(state),
+            True => 1u8.hash(state),
+            False => 2u8.hash(state),
+            Int(i) => { 3u8.hash(state); i.hash(state) },
+            // BigInts that fit in an Int are = to it, so hash the same
+            BigInt(ref b) => {
+                3u8.hash(state);
+                match b.to_isize() {
+                    Some(i) => i.hash(state),
+                    None => b.hash(state),
+                }
+            },
+            Ratio(ref r) => { 12u8.hash(state); r.hash(state) },
+            Float(f) => { 13u8.hash(state); float_bits(f).hash(state) },
+            Strn(ref s) => { 4u8.hash(state); s.hash(state) },
+            Sym(ref s) => { 5u8.hash(state); s.hash(state) },
+            Local(ref s, d, i) => { 15u8.hash(state); s.hash(state); d.hash(state); i.hash(state) },
+            Code(ref proto) => { 16u8.hash(state); (&**proto as *const Proto).hash(state) },
+            Expanded(ref site) => { 17u8.hash(state); (&**site as *const Expansion).hash(state) },
+            #[cfg(feature = "threads")]
+            Promise(ref p) => { 18u8.hash(state); (&**p as *const threads::Promise).hash(state) },
+            Keyword(ref k) => { 6u8.hash(state); k.hash(state) },
+            List(ref v,_) | Vector(ref v,_) => {
+                7u8.hash(state);
+                v.len().hash(state);
+                for mv in v.iter() { mv.hash(state); }
+            },
+            Hash_Map(ref hm,_) => {
+                8u8.hash(state);
+                hm.len().hash(state);
+                let mut sum: u64 = 0;
+                for (k, v) in hm.iter() {
+                    let mut h = DefaultHasher::new();
+                    k.hash(&mut h);
+                    v.hash(&mut h);
+                    sum = sum.wrapping_add(h.finish());
+                }
+                sum.hash(state);
+            },
+            Func(ref fd,_) => {
+                9u8.hash(state);
+                (Rc::as_ptr(&fd.f) as *const u8).hash(state);
+            },
+            MalFunc(ref mf,_) => {
+                10u8.hash(state);
+                (&*mf.exp as *const MalType).hash(state);
+            },
+            Atom(_) => {
+                11u8.hash(state);
+                (self as *const MalType).hash(state);
+            },
+            ExInfo(ref msg, ref data, ref cause) => {
+                14u8.hash(state);
+                msg.hash(state);
+                data.hash(state);
+                cause.hash(state);
+            },
         }
     }
 }
@@ -157,7 +578,21 @@ impl fmt::Debug for MalType {
 
 
 // Scalars
-pub fn _nil() -> MalVal { Rc::new(Nil) }
+//
+// nil, the booleans and small integers are shared rather than
+// allocated every time one is made, as are symbols and keywords (see
+// Symbol::new and keyword)
+const MIN_SHARED_INT: isize = -128;
+const MAX_SHARED_INT: isize = 1023;
+
+thread_local! {
+    static NIL: MalVal = Rc::new(Nil);
+    static TRUE: MalVal = Rc::new(True);
+    static FALSE: MalVal = Rc::new(False);
+    static INTS: Vec<MalVal> = (MIN_SHARED_INT..=MAX_SHARED_INT).map(|i| Rc::new(Int(i))).collect();
+}
+
+pub fn _nil() -> MalVal { NIL.with(|v| v.clone()) }
 pub fn nil_q(a:Vec<MalVal>) -> MalRet {
     if a.len() != 1 {
         return err_str("Wrong arity to nil? call");
@@ -168,7 +603,7 @@ pub fn nil_q(a:Vec<MalVal>) -> MalRet {
     }
 }
 
-pub fn _true() -> MalVal { Rc::new(True) }
+pub fn _true() -> MalVal { TRUE.with(|v| v.clone()) }
 pub fn true_q(a:Vec<MalVal>) -> MalRet {
     if a.len() != 1 {
         return err_str("Wrong arity to true? call");
@@ -179,7 +614,7 @@ pub fn true_q(a:Vec<MalVal>) -> MalRet {
     }
 }
 
-pub fn _false() -> MalVal { Rc::new(False) }
+pub fn _false() -> MalVal { FALSE.with(|v| v.clone()) }
 pub fn false_q(a:Vec<MalVal>) -> MalRet {
     if a.len() != 1 {
         return err_str("Wrong arity to false? call");
@@ -195,31 +630,234 @@ pub fn string_q(a:Vec<MalVal>) -> MalRet {
         return err_str("Wrong arity to string? call");
     }
     match *a[0].clone() {
-        Strn(ref s) => {
-            if s.starts_with("\u{29e}") {
-                Ok(_false())
-            } else {
-                Ok(_true())
-            }
-        },
-        _ => Ok(_false()),
+        Strn(_) => Ok(_true()),
+        _       => Ok(_false()),
     }
 }
 
-pub fn _int(i: isize) -> MalVal { Rc::new(Int(i)) }
+pub fn _int(i: isize) -> MalVal {
+    if (MIN_SHARED_INT..=MAX_SHARED_INT).contains(&i) {
+        INTS.with(|ints| ints[(i - MIN_SHARED_INT) as usize].clone())
+    } else {
+        Rc::new(Int(i))
+    }
+}
+pub fn _bigint(b: num::BigInt) -> MalVal { Rc::new(BigInt(b)) }
+pub fn _float(f: f64) -> MalVal { Rc::new(Float(f)) }
+
+// Ratios with a denominator of 1 are stored as integers, as an Int
+// when they fit in one
+pub fn _ratio(r: num::BigRational) -> MalVal {
+    if r.is_integer() {
+        let b = r.to_integer();
+        match b.to_isize() {
+            Some(i) => _int(i),
+            None => _bigint(b),
+        }
+    } else {
+        Rc::new(Ratio(r))
+    }
+}
 
 
 // Symbols
-pub fn symbol(strn: &str) -> MalVal { Rc::new(Sym(strn.to_string())) }
+
+// Symbols are interned, giving each name a small integer id, so that
+// comparing and hashing them (as every variable lookup does) doesn't
+// have to look at the name
+#[derive(Clone)]
+pub struct Symbol {
+    id:   u32,
+    name: Rc<str>,
+}
+
+// Interned symbols, and the one symbol value made for each. A symbol
+// nothing refers to any more is dropped from the table when it next
+// sweeps, and its id is given to a new symbol, so that reading or
+// making symbols from untrusted input doesn't grow the table forever.
+struct Symbols {
+    ids:      HashMap<Rc<str>, u32>,
+    vals:     Vec<Option<MalVal>>,
+    free:     Vec<u32>,
+    sweep_at: usize,  // number of symbols at which to sweep
+}
+
+// Sweep the table once it holds this many symbols, or twice as many as
+// survived the last sweep if that is more
+const MIN_SWEEP: usize = 10000;
+
+impl Symbols {
+    fn new() -> Symbols {
+        Symbols{ids: HashMap::new(), vals: vec![], free: vec![], sweep_at: MIN_SWEEP}
+    }
+
+    // Drop the symbols only the table refers to: their value is held
+    // by nothing else, and their name only by the value and the key.
+    // New references can only be made through the table, so none can
+    // appear while it is being swept.
+    fn sweep(&mut self) {
+        for (id, slot) in self.vals.iter_mut().enumerate() {
+            let dead = match *slot {
+                Some(ref val) => match **val {
+                    Sym(ref sym) => Rc::strong_count(val) == 1 && Rc::strong_count(&sym.name) == 2,
+                    _ => false,
+                },
+                None => false,
+            };
+            if dead {
+                if let Some(val) = slot.take() {
+                    if let Sym(ref sym) = *val {
+                        self.ids.remove(&sym.name);
+                    }
+                }
+                self.free.push(id as u32);
+            }
+        }
+        self.sweep_at = MIN_SWEEP.max(2 * self.ids.len());
+    }
+}
+
+// Symbols are interned once for all threads when values are shared
+// between them, so that their ids agree
+#[cfg(not(feature = "threads"))]
+thread_local! {
+    static SYMBOLS: cell::RefCell<Symbols> = cell::RefCell::new(Symbols::new());
+}
+
+#[cfg(feature = "threads")]
+static SYMBOLS: LazyLock<Mutex<Symbols>> = LazyLock::new(|| Mutex::new(Symbols::new()));
+
+#[cfg(not(feature = "threads"))]
+fn with_symbols<R, F: FnOnce(&mut Symbols) -> R>(f: F) -> R {
+    SYMBOLS.with(|syms| f(&mut syms.borrow_mut()))
+}
+
+#[cfg(feature = "threads")]
+fn with_symbols<R, F: FnOnce(&mut Symbols) -> R>(f: F) -> R {
+    f(&mut SYMBOLS.lock().unwrap_or_else(|e| e.into_inner()))
+}
+
+// How many symbols are interned, for memory-stats
+pub fn symbol_count() -> usize {
+    with_symbols(|syms| syms.ids.len())
+}
+
+impl Symbol {
+    pub fn new(name: &str) -> Symbol {
+        let val = with_symbols(|syms| {
+            if let Some(&id) = syms.ids.get(name) {
+                if let Some(ref val) = syms.vals[id as usize] {
+                    return val.clone();
+                }
+            }
+            if syms.ids.len() >= syms.sweep_at {
+                syms.sweep();
+            }
+            let id = match syms.free.pop() {
+                Some(id) => id,
+                None => {
+                    syms.vals.push(None);
+                    (syms.vals.len() - 1) as u32
+                },
+            };
+            let sym = Symbol{id, name: Rc::from(name)};
+            syms.ids.insert(sym.name.clone(), id);
+            let val = Rc::new(Sym(sym));
+            syms.vals[id as usize] = Some(val.clone());
+            val
+        });
+        match *val {
+            Sym(ref sym) => sym.clone(),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    // The symbol as a value
+    pub fn to_val(&self) -> MalVal {
+        // self holds the name, so the symbol hasn't been swept
+        with_symbols(|syms| syms.vals[self.id as usize].clone().expect("symbol swept while in use"))
+    }
+}
+
+impl std::ops::Deref for Symbol {
+    type Target = str;
+    fn deref(&self) -> &str {
+        &self.name
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Symbol) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Symbol {}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        &*self.name == other
+    }
+}
+
+impl<'a> PartialEq<&'a str> for Symbol {
+    fn eq(&self, other: &&'a str) -> bool {
+        &*self.name == *other
+    }
+}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u32(self.id);
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+// Hasher for maps keyed by symbol, which only ever hash their id
+#[derive(Default)]
+pub struct IdHasher(u64);
+
+impl Hasher for IdHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 << 8 | b as u64).wrapping_mul(0x9E3779B97F4A7C15);
+        }
+    }
+    fn write_u32(&mut self, id: u32) {
+        self.0 = (id as u64).wrapping_mul(0x9E3779B97F4A7C15);
+    }
+}
+
+pub type SymMap<V> = HashMap<Symbol, V, BuildHasherDefault<IdHasher>>;
+
+pub fn symbol(strn: &str) -> MalVal { Symbol::new(strn).to_val() }
 pub fn _symbol(a: Vec<MalVal>) -> MalRet {
     if a.len() != 1 {
         return err_str("Wrong arity to symbol call");
     }
     match *a[0].clone() {
         Strn(ref s) => {
-            Ok(Rc::new(Sym(s.to_string())))
+            Ok(symbol(s))
         },
-        _ => return err_str("symbol called on non-string"),
+        _ => err_str("symbol called on non-string"),
     }
 }
 pub fn symbol_q(a:Vec<MalVal>) -> MalRet {
@@ -233,12 +871,30 @@ pub fn symbol_q(a:Vec<MalVal>) -> MalRet {
 }
 
 // Keywords
+thread_local! {
+    static KEYWORDS: cell::RefCell<HashMap<Rc<str>, MalVal>> = cell::RefCell::new(HashMap::new());
+}
+
+// Keywords are interned so each one is only allocated once
+pub fn keyword(name: &str) -> MalVal {
+    KEYWORDS.with(|kws| {
+        let mut kws = kws.borrow_mut();
+        if let Some(k) = kws.get(name) {
+            return k.clone();
+        }
+        let name: Rc<str> = Rc::from(name);
+        let k = Rc::new(Keyword(name.clone()));
+        kws.insert(name, k.clone());
+        k
+    })
+}
 pub fn _keyword(a: Vec<MalVal>) -> MalRet {
     if a.len() != 1 {
         return err_str("Wrong arity to keyword call");
     }
     match *a[0] {
-        Strn(ref s) => Ok(Rc::new(Strn(format!("\u{29e}{}", s)))),
+        Strn(ref s) => Ok(keyword(s)),
+        Keyword(_) => Ok(a[0].clone()),
         _ => err_str("keyword called on non-string"),
     }
 }
@@ -247,26 +903,22 @@ pub fn keyword_q(a:Vec<MalVal>) -> MalRet {
         return err_str("Wrong arity to keyword? call");
     }
     match *a[0].clone() {
-        Strn(ref s) => {
-            if s.starts_with("\u{29e}") {
-                Ok(_true())
-            } else {
-                Ok(_false())
-            }
-        },
-        _ => Ok(_false()),
+        Keyword(_) => Ok(_true()),
+        _          => Ok(_false()),
     }
 }
 
 
 // Strings
-pub fn strn(strn: &str) -> MalVal { Rc::new(Strn(strn.to_string())) }
-pub fn string(strn: String) -> MalVal { Rc::new(Strn(strn)) }
+pub fn strn(strn: &str) -> MalVal { Rc::new(Strn(Rc::from(strn))) }
+pub fn string(strn: String) -> MalVal { Rc::new(Strn(Rc::from(strn))) }
 
 // Lists
-pub fn list(seq: Vec<MalVal>) -> MalVal { Rc::new(List(seq,_nil())) }
-pub fn listm(seq: Vec<MalVal>, meta: MalVal) -> MalVal {
-    Rc::new(List(seq,meta))
+pub fn list<S: Into<MalVec>>(seq: S) -> MalVal {
+    Rc::new(List(seq.into(),_nil()))
+}
+pub fn listm<S: Into<MalVec>>(seq: S, meta: MalVal) -> MalVal {
+    Rc::new(List(seq.into(),meta))
 }
 pub fn listv(seq:Vec<MalVal>) -> MalRet { Ok(list(seq)) }
 pub fn list_q(a:Vec<MalVal>) -> MalRet {
@@ -280,9 +932,11 @@ pub fn list_q(a:Vec<MalVal>) -> MalRet {
 }
 
 // Vectors
-pub fn vector(seq: Vec<MalVal>) -> MalVal { Rc::new(Vector(seq,_nil())) }
-pub fn vectorm(seq: Vec<MalVal>, meta: MalVal) -> MalVal {
-    Rc::new(Vector(seq,meta))
+pub fn vector<S: Into<MalVec>>(seq: S) -> MalVal {
+    Rc::new(Vector(seq.into(),_nil()))
+}
+pub fn vectorm<S: Into<MalVec>>(seq: S, meta: MalVal) -> MalVal {
+    Rc::new(Vector(seq.into(),meta))
 }
 pub fn vectorv(seq: Vec<MalVal>) -> MalRet { Ok(vector(seq)) }
 pub fn vector_q(a:Vec<MalVal>) -> MalRet {
@@ -296,49 +950,31 @@ pub fn vector_q(a:Vec<MalVal>) -> MalRet {
 }
 
 // Hash Maps
-pub fn hash_map(hm: HashMap<String,MalVal>) -> MalVal {
+pub fn hash_map(hm: MalMap) -> MalVal {
     Rc::new(Hash_Map(hm,_nil()))
 }
-pub fn hash_mapm(hm: HashMap<String,MalVal>, meta: MalVal) -> MalVal {
+pub fn hash_mapm(hm: MalMap, meta: MalVal) -> MalVal {
     Rc::new(Hash_Map(hm,meta))
 }
-pub fn _assoc(hm: &HashMap<String,MalVal>, a:Vec<MalVal>) -> MalRet {
+pub fn _assoc(hm: &MalMap, a:Vec<MalVal>) -> MalRet {
     if a.len() % 2 == 1 {
         return err_str("odd number of hash-map keys/values");
     }
     let mut new_hm = hm.clone();
-    let mut it = a.iter();
-    loop {
-        let k = match it.next() {
-            Some(mv) => match *mv.clone() {
-                Strn(ref s) => s.to_string(),
-                _ => return err_str("key is not a string in hash-map call"),
-            },
-            None => break,
-        };
-        let v = it.next().unwrap();
-        new_hm.insert(k, v.clone());
+    for kv in a.chunks(2) {
+        new_hm.insert(kv[0].clone(), kv[1].clone());
     }
     Ok(Rc::new(Hash_Map(new_hm,_nil())))
 }
-pub fn _dissoc(hm: &HashMap<String,MalVal>, a:Vec<MalVal>) -> MalRet {
+pub fn _dissoc(hm: &MalMap, a:Vec<MalVal>) -> MalRet {
     let mut new_hm = hm.clone();
-    let mut it = a.iter();
-    loop {
-        let k = match it.next() {
-            Some(mv) => match *mv.clone() {
-                Strn(ref s) => s.to_string(),
-                _ => return err_str("key is not a string in hash-map call"),
-            },
-            None => break,
-        };
-        new_hm.remove(&k);
+    for k in a.iter() {
+        new_hm.remove(k);
     }
     Ok(Rc::new(Hash_Map(new_hm,_nil())))
 }
 pub fn hash_mapv(seq: Vec<MalVal>) -> MalRet {
-    let new_hm: HashMap<String,MalVal> = HashMap::new();
-    _assoc(&new_hm, seq)
+    _assoc(&MalMap::new(), seq)
 }
 pub fn hash_map_q(a:Vec<MalVal>) -> MalRet {
     if a.len() != 1 {
@@ -352,27 +988,42 @@ pub fn hash_map_q(a:Vec<MalVal>) -> MalRet {
 
 // Functions
 pub fn func(f: fn(Vec<MalVal>) -> MalRet) -> MalVal {
-    Rc::new(Func(f, _nil()))
+    native_fn("", f)
+}
+pub fn native_fn<F>(name: &str, f: F) -> MalVal
+    where F: Fn(Vec<MalVal>) -> MalRet + Shared + 'static
+{
+    Rc::new(Func(FuncData{name: name.to_string(), f: Rc::new(f)}, _nil()))
 }
-pub fn funcm(f: fn(Vec<MalVal>) -> MalRet, meta: MalVal) -> MalVal {
-    Rc::new(Func(f, meta))
+pub fn funcd(fd: FuncData, meta: MalVal) -> MalVal {
+    Rc::new(Func(fd, meta))
 }
 pub fn malfunc(eval: fn(MalVal, Env) -> MalRet,
                exp: MalVal,
                env: Env,
                params: MalVal,
                meta: MalVal) -> MalVal {
-    Rc::new(MalFunc(MalFuncData{eval: eval,
-                                exp: exp,
-                                env: env,
-                                params: params,
-                                is_macro: false},meta))
+    gc::track_env(&env);
+    Rc::new(MalFunc(MalFuncData{name: String::new(),
+                                eval,
+                                exp,
+                                env,
+                                params,
+                                is_macro: false,
+                                arities: vec![]},meta))
 }
 pub fn malfuncd(mfd: MalFuncData, meta: MalVal) -> MalVal {
+    gc::track_env(&mfd.env);
     Rc::new(MalFunc(mfd,meta))
 }
 
 
+// Exceptions
+pub fn ex_info(msg: String, data: MalVal, cause: MalVal) -> MalVal {
+    Rc::new(ExInfo(msg, data, cause))
+}
+
+
 // Atoms
 pub fn atom_q(a:Vec<MalVal>) -> MalRet {
     if a.len() != 1 {
@@ -387,7 +1038,9 @@ pub fn atom(a:Vec<MalVal>) -> MalRet {
     if a.len() != 1 {
         return err_str("Wrong arity to atom call");
     }
-    Ok(Rc::new(Atom(RefCell::new(a[0].clone()))))
+    let atom = Rc::new(Atom(RefCell::new(a[0].clone())));
+    gc::track_atom(&atom);
+    Ok(atom)
 }
 
 
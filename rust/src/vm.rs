@@ -0,0 +1,410 @@
+// Stack VM running the bytecode made by the compiler.
+//
+// Calls from one VM closure to another push a frame here rather than
+// nesting on the native stack, and calls in tail position replace the
+// current frame. Anything else (native functions, closures made by the
+// tree-walker, try* bodies and handlers) is called nested, as the
+// tree-walker would.
+
+use std::mem;
+
+use sync::Rc;
+use types::{MalVal, MalRet, MalVec, MalMap, MalError, MalFuncData, ErrorKind, form_pos,
+            _nil, list, vector, hash_map, malfuncd};
+use types::MalType::{Nil, False, Sym, List, Vector, Hash_Map, Func, MalFunc, Code};
+use env::{env_new, env_outer, env_bind, env_bind_recur, env_bind_local, env_destructure,
+          env_get_local, Env};
+use compiler::{self, Proto, Op};
+use eval::{self, check_depth, DepthGuard};
+use budget;
+use namespace;
+
+/// Evaluate a form by compiling it and running the result.
+///
+/// A `do` or `if` is run a subform at a time instead, each compiled
+/// only once it is reached, so that the macros in it are expanded when
+/// the tree-walker would expand them: after the forms before them have
+/// run, which may have redefined them, and not at all in a branch that
+/// isn't taken.
+pub fn eval(ast: MalVal, env: Env) -> MalRet {
+    if let Some(head) = staged(&ast, &env) {
+        let pos = form_pos(&ast);
+        return run_staged(head, &ast, env).map_err(|e| match pos {
+            Some(p) => e.at(p),
+            None => e,
+        });
+    }
+    match *ast {
+        Sym(_) | List(_,_) | Vector(_,_) | Hash_Map(_,_) => {
+            let proto = compiler::compile(&ast, &env);
+            run(&proto, env)
+        },
+        Code(ref proto) => run(&compiler::current(proto, &env), env),
+        _ => Ok(ast.clone()),
+    }
+}
+
+// The special form eval runs itself, if ast is one it can
+fn staged(ast: &MalVal, env: &Env) -> Option<&'static str> {
+    let items = match **ast {
+        List(ref items,_) if !items.is_empty() => items,
+        _ => return None,
+    };
+    let head = match *items[0] {
+        Sym(ref s) if *s == "do" => "do",
+        Sym(ref s) if *s == "if" => "if",
+        _ => return None,
+    };
+    if compiler::is_macro(&items[0], env) || eval::check_syntax(head, items).is_err() {
+        return None;
+    }
+    Some(head)
+}
+
+// Run a do or if, nesting the subforms whose value it uses as the
+// compiled form would
+fn run_staged(head: &str, ast: &MalVal, env: Env) -> MalRet {
+    budget::step()?;
+    let items = match **ast {
+        List(ref items,_) => items,
+        _ => unreachable!(),
+    };
+    if head == "do" {
+        let last = items.len() - 1;
+        if last == 0 {
+            return Ok(_nil());
+        }
+        for item in items.iter().take(last).skip(1) {
+            let _nested = eval::nest(1);
+            eval(item.clone(), env.clone())?;
+        }
+        return eval(items[last].clone(), env);
+    }
+    let test = {
+        let _nested = eval::nest(1);
+        eval(items[1].clone(), env.clone())?
+    };
+    match *test {
+        Nil | False => match items.get(3) {
+            Some(alt) => eval(alt.clone(), env),
+            None => Ok(_nil()),
+        },
+        _ => eval(items[2].clone(), env),
+    }
+}
+
+// The eval function of closures made by the VM, whose body is Code
+pub fn eval_body(exp: MalVal, env: Env) -> MalRet {
+    match *exp {
+        Code(ref proto) => run(&compiler::current(proto, &env), env),
+        _ => eval::walk(exp.clone(), env),
+    }
+}
+
+struct CallFrame {
+    proto:  Rc<Proto>,
+    pc:     usize,
+    env:    Env,
+    base:   usize,                       // stack height when the frame was entered
+    func:   Option<MalVal>,              // the function this is a call of
+    caller: Option<(Rc<Proto>, usize)>,  // and the op it was called from
+    levels: usize,                       // of nesting the frame counts for
+    _depth: DepthGuard,
+}
+
+impl CallFrame {
+    fn new(proto: Rc<Proto>, env: Env, base: usize, levels: usize) -> CallFrame {
+        CallFrame{proto, pc: 0, env, base, func: None, caller: None,
+                  levels, _depth: eval::nest(levels)}
+    }
+}
+
+/// Run a compiled form in env.
+pub fn run(proto: &Rc<Proto>, env: Env) -> MalRet {
+    budget::step()?;
+    let mut vm = Vm{stack: Vec::with_capacity(16), frames: vec![]};
+    let mut frame = CallFrame::new(proto.clone(), env, 0, 1);
+    match vm.execute(&mut frame) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(vm.unwind(frame, e)),
+    }
+}
+
+struct Vm {
+    stack:  Vec<MalVal>,
+    frames: Vec<CallFrame>,  // callers of the current frame
+}
+
+// What a call does: give a value to the caller, or enter a frame
+enum Called {
+    Value(MalVal),
+    Frame(CallFrame),
+}
+
+impl Vm {
+    fn pop(&mut self) -> MalVal {
+        self.stack.pop().expect("VM stack underflow")
+    }
+
+    fn pop_n(&mut self, n: usize) -> Vec<MalVal> {
+        let at = self.stack.len() - n;
+        self.stack.split_off(at)
+    }
+
+    fn execute(&mut self, f: &mut CallFrame) -> MalRet {
+        loop {
+            let op = f.proto.code[f.pc];
+            f.pc += 1;
+            match op {
+                Op::Const(k) => self.stack.push(f.proto.consts[k].clone()),
+                Op::Local(depth, index, k) => {
+                    let val = match *f.proto.consts[k] {
+                        Sym(ref sym) => env_get_local(&f.env, sym, depth, index)?,
+                        _ => _nil(),
+                    };
+                    self.stack.push(val);
+                },
+                Op::Global(k) => {
+                    let val = namespace::resolve(&f.env, &f.proto.consts[k])?;
+                    self.stack.push(val);
+                },
+                Op::Vector(n) => {
+                    let items: MalVec = self.pop_n(n).into_iter().collect();
+                    self.stack.push(vector(items));
+                },
+                Op::Map(n) => {
+                    let mut hm = MalMap::new();
+                    let mut kvs = self.pop_n(2 * n).into_iter();
+                    while let (Some(k), Some(v)) = (kvs.next(), kvs.next()) {
+                        hm.insert(k, v);
+                    }
+                    self.stack.push(hash_map(hm));
+                },
+                Op::Closure(i) => {
+                    let t = &f.proto.fns[i];
+                    let mf = malfuncd(MalFuncData{name: String::new(),
+                                                  eval: eval_body,
+                                                  exp: t.exp.clone(),
+                                                  env: f.env.clone(),
+                                                  params: t.params.clone(),
+                                                  is_macro: false,
+                                                  arities: t.arities.clone()}, _nil());
+                    self.stack.push(mf);
+                },
+                Op::Pop => { self.pop(); },
+                Op::Jump(pc) => f.pc = pc,
+                Op::JumpIfFalse(pc) => {
+                    match *self.pop() {
+                        False | Nil => f.pc = pc,
+                        _ => (),
+                    }
+                },
+                Op::MacroCheck(k, pc) => {
+                    let is_macro = match **self.stack.last().expect("VM stack underflow") {
+                        MalFunc(ref mf,_) => mf.is_macro,
+                        _ => false,
+                    };
+                    if is_macro {
+                        self.pop();
+                        let _nested = eval::nest(f.proto.nest(f.pc - 1).saturating_sub(1));
+                        let res = eval(f.proto.consts[k].clone(), f.env.clone())?;
+                        self.stack.push(res);
+                        f.pc = pc;
+                    }
+                },
+                Op::Call(n) => {
+                    match self.call(f, n, false)? {
+                        Called::Value(res) => self.stack.push(res),
+                        Called::Frame(callee) => {
+                            let caller = mem::replace(f, callee);
+                            self.frames.push(caller);
+                        },
+                    }
+                },
+                Op::TailCall(n) => {
+                    match self.call(f, n, true)? {
+                        Called::Value(res) => {
+                            if let Some(res) = self.ret(f, res) {
+                                return Ok(res);
+                            }
+                        },
+                        // the caller of this frame gets the callee's value
+                        Called::Frame(callee) => { *f = callee; },
+                    }
+                },
+                Op::Return => {
+                    let res = self.pop();
+                    if let Some(res) = self.ret(f, res) {
+                        return Ok(res);
+                    }
+                },
+                Op::PushEnv => f.env = env_new(Some(f.env.clone())),
+                Op::PopEnv => {
+                    f.env = env_outer(&f.env).expect("PopEnv without PushEnv");
+                },
+                Op::Bind(k) => {
+                    let val = self.pop();
+                    if let Err(e) = env_destructure(&f.env, &f.proto.consts[k], val) {
+                        return Err(MalError::runtime(e));
+                    }
+                },
+                Op::Recur(n, depth, target) => {
+                    budget::step()?;
+                    let args = list(self.pop_n(n));
+                    let mut env = f.env.clone();
+                    for _ in 0..depth {
+                        env = env_outer(&env).expect("recur target outside env");
+                    }
+                    let new_env = env_new(env_outer(&env));
+                    let (ref params, start, _) = f.proto.loops[target];
+                    if let Err(e) = env_bind_recur(&new_env, params.clone(), args) {
+                        return Err(MalError::runtime(e));
+                    }
+                    f.pc = start;
+                    // as the tree-walker would, go round again with any
+                    // macro redefined on the way expanded anew
+                    if let Some(p) = compiler::resume(&f.proto, target, &new_env) {
+                        f.proto = p;
+                    }
+                    f.env = new_env;
+                },
+                Op::Def(s, m) => {
+                    let val = self.pop();
+                    let res = eval::define(&f.env, &f.proto.consts[s],
+                                                &f.proto.consts[m], val)?;
+                    self.stack.push(res);
+                },
+                Op::DefMacro(s) => {
+                    let val = self.pop();
+                    let res = eval::define_macro(&f.env, &f.proto.consts[s], val)?;
+                    self.stack.push(res);
+                },
+                Op::Try(body, handler) => {
+                    let _nested = eval::nest(f.proto.nest(f.pc - 1));
+                    let res = match run(&f.proto.subs[body], f.env.clone()) {
+                        Ok(res) => res,
+                        Err(e) => match handler {
+                            Some((h, k)) if e.kind != ErrorKind::Budget => {
+                                let bind_env = env_new(Some(f.env.clone()));
+                                env_bind_local(&bind_env, f.proto.consts[k].clone(), e.to_val());
+                                run(&f.proto.subs[h], bind_env)?
+                            },
+                            _ => return Err(e),
+                        },
+                    };
+                    self.stack.push(res);
+                },
+                Op::Dynamic(k) => { eval::dynamic_var(&f.env, &f.proto.consts[k])?; },
+                Op::Binding(k, body) => {
+                    let names = match *f.proto.consts[k] {
+                        List(ref names,_) => names.clone(),
+                        _ => MalVec::new(),
+                    };
+                    let vals = self.pop_n(names.len());
+                    let mut vars = vec![];
+                    for (sym, val) in names.iter().zip(vals) {
+                        let (var_env, name) = eval::dynamic_var(&f.env, sym)?;
+                        vars.push((var_env, name, val));
+                    }
+                    let body = &f.proto.subs[body];
+                    let _nested = eval::nest(f.proto.nest(f.pc - 1));
+                    let res = eval::with_bindings(vars, || run(body, f.env.clone()))?;
+                    self.stack.push(res);
+                },
+                Op::Eval => {
+                    let form = self.pop();
+                    let _nested = eval::nest(f.proto.nest(f.pc - 1).saturating_sub(1));
+                    let res = eval::eval(form, namespace::eval_env(&f.env))?;
+                    self.stack.push(res);
+                },
+                Op::Macroexpand(k) => {
+                    let _nested = eval::nest(f.proto.nest(f.pc - 1));
+                    let res = eval::macroexpand(f.proto.consts[k].clone(), f.env.clone())?;
+                    self.stack.push(res);
+                },
+                Op::Interpret(k) => {
+                    let _nested = eval::nest(f.proto.nest(f.pc - 1).saturating_sub(1));
+                    let res = eval::walk(f.proto.consts[k].clone(), f.env.clone())?;
+                    self.stack.push(res);
+                },
+                Op::Fail(k) => return Err(compiler::failure(&f.proto, k)),
+            }
+        }
+    }
+
+    // Return res from the current frame to its caller, or give it back
+    // if the frame is the one run was called with
+    fn ret(&mut self, f: &mut CallFrame, res: MalVal) -> Option<MalVal> {
+        self.stack.truncate(f.base);
+        match self.frames.pop() {
+            Some(caller) => {
+                *f = caller;
+                self.stack.push(res);
+                None
+            },
+            None => Some(res),
+        }
+    }
+
+    // Call the function below the top n values of the stack, from the
+    // op before f.pc. A closure made by the VM gets a frame to run in,
+    // which replaces f if the call is in tail position.
+    //
+    // The depth of nesting is counted as in the tree-walker, which
+    // evaluates the call at the op's nest levels deeper than f and
+    // continues into a closure's body at that level.
+    fn call(&mut self, f: &CallFrame, n: usize, tail: bool) -> Result<Called, MalError> {
+        budget::step()?;
+        let args = self.pop_n(n);
+        let callee = self.pop();
+        let call_pc = f.pc - 1;
+        let nested = eval::nest(f.proto.nest(call_pc));
+        match *callee {
+            Func(ref fd,_) => {
+                budget::charged(&*fd.f, args)
+                    .map(Called::Value)
+                    .map_err(|e| e.in_frame(&fd.name, f.proto.pos(call_pc)))
+            },
+            MalFunc(ref mf,_) => {
+                let arity = check_depth(&mf.name).and_then(|_| mf.arity(args.len()));
+                let (params, exp) = match arity {
+                    Ok(arity) => arity,
+                    Err(e) => return Err(e.in_frame(&mf.name, f.proto.pos(call_pc))),
+                };
+                let proto = match *exp {
+                    Code(ref proto) => compiler::current(proto, &mf.env),
+                    _ => return callee.apply_at(args, f.proto.pos(call_pc)).map(Called::Value),
+                };
+                let new_env = env_new(Some(mf.env.clone()));
+                if let Err(e) = env_bind(&new_env, params, list(args)) {
+                    return Err(MalError::runtime(e).in_frame(&mf.name, f.proto.pos(call_pc)));
+                }
+                let levels = f.proto.nest(call_pc) + if tail { f.levels } else { 0 };
+                let mut frame = CallFrame::new(proto, new_env, self.stack.len(), levels);
+                drop(nested);
+                frame.caller = Some((f.proto.clone(), call_pc));
+                frame.func = Some(callee.clone());
+                Ok(Called::Frame(frame))
+            },
+            _ => Err(MalError::runtime("attempt to call non-function".to_string())),
+        }
+    }
+
+    // Add where the error happened to it: the position of the op each
+    // frame was at, and the function calls it passed out of
+    fn unwind(&mut self, mut f: CallFrame, mut e: MalError) -> MalError {
+        loop {
+            if let Some(pos) = f.proto.pos(f.pc.saturating_sub(1)) {
+                e = e.at(pos);
+            }
+            if let Some(MalFunc(ref mf,_)) = f.func.as_deref() {
+                let pos = f.caller.as_ref().and_then(|&(ref proto, pc)| proto.pos(pc));
+                e = e.in_frame(&mf.name, pos);
+            }
+            f = match self.frames.pop() {
+                Some(caller) => caller,
+                None => return e,
+            };
+        }
+    }
+}
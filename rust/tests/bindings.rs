@@ -0,0 +1,37 @@
+// Dynamic bindings are undone when the code they are in effect for
+// panics, however the panic is recovered from
+
+extern crate mal;
+
+use std::panic;
+
+use mal::env::env_unbound;
+use mal::interpreter::Interpreter;
+use mal::types::_true;
+
+fn interpreter() -> Interpreter {
+    let interp = Interpreter::new();
+    interp.eval_str("(def! ^:dynamic *x* 0)").unwrap();
+    interp
+}
+
+#[test]
+fn binding_body_panics() {
+    let interp = interpreter();
+    interp.define_fn("boom", |_| panic!("boom"));
+    let err = interp.eval_str("(binding [*x* 1] (boom))").unwrap_err();
+    assert_eq!(err.message(), "internal error: boom");
+    assert_eq!(interp.eval_str("*x*").unwrap().pr_str(true), "0");
+}
+
+#[test]
+fn unbound_code_panics() {
+    let interp = interpreter();
+    interp.define_fn("unbound-boom", |_| {
+        assert!(panic::catch_unwind(|| env_unbound(|| panic!("boom"))).is_err());
+        Ok(_true())
+    });
+    let res = interp.eval_str("(binding [*x* 1] (do (unbound-boom) *x*))").unwrap();
+    assert_eq!(res.pr_str(true), "1");
+    assert_eq!(interp.eval_str("*x*").unwrap().pr_str(true), "0");
+}
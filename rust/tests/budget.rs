@@ -0,0 +1,150 @@
+// Running out of an execution budget or of evaluation depth, in both
+// eval modes
+
+extern crate mal;
+
+use std::time::{Duration, Instant};
+
+use mal::budget::Budget;
+use mal::eval::Mode;
+use mal::interpreter::Interpreter;
+use mal::types::ErrorKind;
+
+const MODES: [Mode; 2] = [Mode::Bytecode, Mode::Tree];
+
+fn interpreter(mode: Mode) -> Interpreter {
+    let interp = Interpreter::new();
+    interp.set_mode(mode);
+    interp
+}
+
+#[test]
+fn step_budget_exceeded() {
+    for &mode in MODES.iter() {
+        let interp = interpreter(mode);
+        interp.eval_str("(def! spin (fn* (n) (if (= n 0) 0 (spin (- n 1)))))").unwrap();
+        interp.set_budget(&Budget{max_steps: Some(1000), ..Budget::default()});
+        let err = interp.eval_str("(spin 100000)").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Budget, "{:?}", mode);
+        assert!(err.message().contains("step limit of 1000 exceeded"), "{:?}: {}", mode, err);
+    }
+}
+
+#[test]
+fn step_budget_not_caught_by_try() {
+    for &mode in MODES.iter() {
+        let interp = interpreter(mode);
+        interp.eval_str("(def! spin (fn* (n) (if (= n 0) 0 (spin (- n 1)))))").unwrap();
+        interp.set_budget(&Budget{max_steps: Some(1000), ..Budget::default()});
+        let err = interp.eval_str("(try* (spin 100000) (catch* e :caught))").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Budget, "{:?}", mode);
+    }
+}
+
+#[test]
+fn step_budget_enough() {
+    for &mode in MODES.iter() {
+        let interp = interpreter(mode);
+        interp.eval_str("(def! spin (fn* (n) (if (= n 0) 0 (spin (- n 1)))))").unwrap();
+        interp.set_budget(&Budget{max_steps: Some(100000), ..Budget::default()});
+        let res = interp.eval_str("(spin 100)").unwrap();
+        assert_eq!(res.pr_str(true), "0", "{:?}", mode);
+    }
+}
+
+#[test]
+fn depth_exceeded() {
+    for &mode in MODES.iter() {
+        let interp = interpreter(mode);
+        interp.set_max_depth(50);
+        interp.eval_str("(def! count-down (fn* (n) (if (= n 0) 0 (+ 1 (count-down (- n 1))))))")
+            .unwrap();
+        assert_eq!(interp.eval_str("(count-down 40)").unwrap().pr_str(true), "40", "{:?}", mode);
+        let err = interp.eval_str("(count-down 100)").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Runtime, "{:?}", mode);
+        assert_eq!(err.message(), "stack overflow calling count-down, max depth is 50",
+                   "{:?}", mode);
+    }
+}
+
+#[test]
+fn timeout_exceeded() {
+    for &mode in MODES.iter() {
+        let interp = interpreter(mode);
+        interp.eval_str("(def! spin (fn* () (loop* [n 0] (recur (+ n 1)))))").unwrap();
+        interp.set_budget(&Budget{timeout: Some(Duration::from_millis(50)), ..Budget::default()});
+        let start = Instant::now();
+        let err = interp.eval_str("(spin)").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Budget, "{:?}", mode);
+        assert!(err.message().contains("time limit of 50ms exceeded"), "{:?}: {}", mode, err);
+        assert!(start.elapsed() < Duration::from_secs(5), "{:?}", mode);
+    }
+}
+
+// Growing a collection directly, and through apply, map and swap!, which
+// call the native function for the evaluator
+#[test]
+fn alloc_budget_exceeded() {
+    let growers = [
+        "(loop* [v []] (recur (conj v 1)))",
+        "(loop* [l ()] (recur (concat l [1 2 3])))",
+        "(loop* [v []] (recur (apply conj v [1])))",
+        "(loop* [] (do (map list [1 2 3 4 5 6 7 8]) (recur)))",
+        "(let* [a (atom [])] (loop* [] (do (swap! a conj 1) (recur))))",
+    ];
+    for &mode in MODES.iter() {
+        for src in growers.iter() {
+            let interp = interpreter(mode);
+            interp.set_budget(&Budget{max_alloc: Some(100000), ..Budget::default()});
+            let err = interp.eval_str(src).unwrap_err();
+            assert_eq!(err.kind, ErrorKind::Budget, "{:?} {}", mode, src);
+            assert!(err.message().contains("allocation limit of 100000 bytes exceeded"),
+                    "{:?} {}: {}", mode, src, err);
+        }
+    }
+}
+
+#[test]
+fn alloc_budget_enough() {
+    for &mode in MODES.iter() {
+        let interp = interpreter(mode);
+        interp.set_budget(&Budget{max_alloc: Some(1000000), ..Budget::default()});
+        let res = interp.eval_str("(count (loop* [v [] n 0] (if (= n 100) v (recur (conj v n) (+ n 1)))))")
+            .unwrap();
+        assert_eq!(res.pr_str(true), "100", "{:?}", mode);
+    }
+}
+
+// Limits set on one interpreter don't apply to another on the same thread
+#[test]
+fn settings_kept_per_interpreter() {
+    let limited = interpreter(Mode::Tree);
+    let free = interpreter(Mode::Bytecode);
+    for interp in [&limited, &free].iter() {
+        interp.eval_str("(def! spin (fn* (n) (if (= n 0) 0 (spin (- n 1)))))").unwrap();
+        interp.eval_str("(def! count-down (fn* (n) (if (= n 0) 0 (+ 1 (count-down (- n 1))))))")
+            .unwrap();
+    }
+    limited.set_budget(&Budget{max_steps: Some(1000), ..Budget::default()});
+    limited.set_max_depth(50);
+
+    assert_eq!(free.eval_str("(spin 10000)").unwrap().pr_str(true), "0");
+    assert_eq!(free.eval_str("(count-down 100)").unwrap().pr_str(true), "100");
+    let err = limited.eval_str("(count-down 100)").unwrap_err();
+    assert_eq!(err.message(), "stack overflow calling count-down, max depth is 50");
+    let err = limited.eval_str("(spin 10000)").unwrap_err();
+    assert_eq!(err.kind, ErrorKind::Budget);
+    // and the budget spent by the limited one isn't reset by the other
+    assert_eq!(free.eval_str("(spin 10)").unwrap().pr_str(true), "0");
+    assert_eq!(limited.eval_str("(spin 10)").unwrap_err().kind, ErrorKind::Budget);
+
+    // A host function evaluating with another interpreter gets that
+    // one's limits, and its own are put back afterwards
+    let free = mal::sync::Rc::new(free);
+    let inner = free.clone();
+    limited.define_fn("free-spin", move |a| inner.eval(mal::types::list(
+        vec![mal::types::symbol("spin"), a[0].clone()])));
+    limited.set_budget(&Budget{max_steps: Some(1000), ..Budget::default()});
+    assert_eq!(limited.eval_str("(free-spin 10000)").unwrap().pr_str(true), "0");
+    assert_eq!(limited.eval_str("(spin 10000)").unwrap_err().kind, ErrorKind::Budget);
+}
@@ -0,0 +1,42 @@
+// The cycle collector leaves values alone while another thread is
+// evaluating through an Interpreter
+
+#![cfg(feature = "threads")]
+
+extern crate mal;
+
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::thread;
+
+use mal::interpreter::Interpreter;
+use mal::sync::Rc;
+
+#[test]
+fn no_collection_while_another_thread_evaluates() {
+    let interp = Rc::new(Interpreter::new());
+    interp.eval_str("(def! mk (fn* [] (let* [a (atom nil)] (reset! a (fn* [] a)))))").unwrap();
+    interp.eval_str("(def! inside (promise)) (def! done (promise))").unwrap();
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    interp.define_fn("ready", move |_| {
+        tx.lock().unwrap().send(()).unwrap();
+        Ok(mal::types::_nil())
+    });
+    let other = {
+        let interp = interp.clone();
+        thread::spawn(move || {
+            interp.eval_str("(do (loop* [n 0] (if (< n 100) (do (mk) (recur (+ n 1))) n)) \
+                                 (ready) \
+                                 @inside \
+                                 (let* [n (gc)] (do (deliver done true) n)))").unwrap()
+        })
+    };
+    // wait for the other thread to be evaluating, holding the
+    // interpreter's tracking, before evaluating here as well
+    rx.recv().unwrap();
+    interp.eval_str("(do (deliver inside true) @done)").unwrap();
+    assert_eq!(other.join().unwrap().pr_str(true), "0");
+    // with no other thread evaluating, the cycles mk made are freed
+    assert!(interp.gc() >= 100);
+}
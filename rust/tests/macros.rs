@@ -0,0 +1,62 @@
+// When macros used at the top level and in loops are expanded, in both
+// eval modes
+
+extern crate mal;
+
+use mal::eval::Mode;
+use mal::interpreter::Interpreter;
+
+const MODES: [Mode; 2] = [Mode::Bytecode, Mode::Tree];
+
+fn eval_in(mode: Mode, src: &str) -> String {
+    let interp = Interpreter::new();
+    interp.set_mode(mode);
+    interp.eval_str(src).unwrap().pr_str(true)
+}
+
+#[test]
+fn redefined_earlier_in_do() {
+    for &mode in MODES.iter() {
+        let res = eval_in(mode, "(do (defmacro! m (fn* [] 1)) (defmacro! m (fn* [] 2)) (m))");
+        assert_eq!(res, "2", "{:?}", mode);
+        let res = eval_in(mode, "(defmacro! m (fn* [] 1)) (do (defmacro! m (fn* [] 2)) (m))");
+        assert_eq!(res, "2", "{:?}", mode);
+    }
+}
+
+#[test]
+fn not_expanded_in_branch_not_taken() {
+    for &mode in MODES.iter() {
+        let res = eval_in(mode, "(def! n (atom 0))
+                                 (defmacro! m (fn* [] (do (swap! n (fn* [x] (+ x 1))) 1)))
+                                 (if false (m) 2)
+                                 @n");
+        assert_eq!(res, "0", "{:?}", mode);
+    }
+}
+
+#[test]
+fn expanded_after_forms_before_it_run() {
+    for &mode in MODES.iter() {
+        let res = eval_in(mode, "(def! log (atom []))
+                                 (defmacro! m (fn* [] (do (swap! log conj :expanded) nil)))
+                                 (do (swap! log conj :first) (m))
+                                 @log");
+        assert_eq!(res, "[:first :expanded]", "{:?}", mode);
+    }
+}
+
+#[test]
+fn redefined_while_looping() {
+    let redefine = "(defmacro! m (fn* [] 1))
+                    (def! redefine (fn* [] (eval '(defmacro! m (fn* [] 2)))))";
+    let body = "(if (< i 3)
+                  (let* [v (m)] (do (if (= i 0) (redefine)) (recur (+ i 1) (conj acc v))))
+                  acc)";
+    for &mode in MODES.iter() {
+        let top = format!("{} (loop* [i 0 acc []] {})", redefine, body);
+        assert_eq!(eval_in(mode, &top), "[1 2 2]", "{:?}", mode);
+        let in_fn = format!("{} ((fn* [] (loop* [i 0 acc []] {})))", redefine, body);
+        assert_eq!(eval_in(mode, &in_fn), "[1 2 2]", "{:?}", mode);
+    }
+}
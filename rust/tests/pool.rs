@@ -0,0 +1,27 @@
+// A worker pool configured with a smaller depth limit, and so a smaller
+// stack for each worker
+
+#![cfg(feature = "threads")]
+
+extern crate mal;
+
+use mal::interpreter::Interpreter;
+use mal::threads::{self, PoolConfig};
+
+#[test]
+fn configured_pool() {
+    assert!(threads::configure_pool(PoolConfig{workers: 2, max_depth: 100}));
+    let interp = Interpreter::new();
+    interp.eval_str("(def! count-down (fn* (n) (if (= n 0) 0 (+ 1 (count-down (- n 1))))))")
+        .unwrap();
+    // a deref with a timeout waits for a worker to run the future,
+    // rather than running it on this thread
+    let res = interp.eval_str("(let* [f (future (count-down 50))] (deref f 10000 :timeout))")
+        .unwrap();
+    assert_eq!(res.pr_str(true), "50");
+    let res = interp.eval_str("(let* [f (future (count-down 200))] \
+                               (try* (deref f 10000 :timeout) (catch* e e)))").unwrap();
+    assert_eq!(res.pr_str(false), "stack overflow calling count-down, max depth is 100");
+    // too late to change it once the pool has started
+    assert!(!threads::configure_pool(PoolConfig::default()));
+}
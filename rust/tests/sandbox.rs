@@ -0,0 +1,94 @@
+// Built-ins that do I/O raise a Permission error when their capability
+// is denied, and still work when it is allowed
+
+extern crate mal;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use mal::interpreter::Interpreter;
+use mal::sandbox::{Capabilities, FileAccess, Output};
+use mal::sync::{Rc, RefCell};
+use mal::types::ErrorKind;
+
+// A directory of its own for each test, holding a.mal
+fn scratch(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("mal-sandbox-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("a.mal"), "(def! x 7)\n\"a.mal done\"\n").unwrap();
+    dir
+}
+
+fn quoted(path: &Path) -> String {
+    format!("{:?}", path.to_str().unwrap())
+}
+
+fn assert_denied(interp: &Interpreter, src: &str, msg: &str) {
+    let err = interp.eval_str(src).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::Permission, "{}", src);
+    assert_eq!(err.message(), msg, "{}", src);
+}
+
+#[test]
+fn nothing_permitted() {
+    let dir = scratch("none");
+    let file = quoted(&dir.join("a.mal"));
+    let interp = Interpreter::with_capabilities(&Capabilities::none());
+    assert_denied(&interp, &format!("(slurp {})", file),
+                  "slurp is not permitted: file access is disabled");
+    assert_denied(&interp, &format!("(load-file {})", file),
+                  "load-file is not permitted: file access is disabled");
+    assert_denied(&interp, "(prn 1)", "prn is not permitted: output is disabled");
+    assert_denied(&interp, "(println 1)", "println is not permitted: output is disabled");
+    assert_denied(&interp, "(readline \"> \")", "readline is not permitted: input is disabled");
+    // Evaluation that does no I/O is unaffected
+    assert_eq!(interp.eval_str("(+ 1 2)").unwrap().pr_str(true), "3");
+}
+
+#[test]
+fn denial_can_be_caught() {
+    let interp = Interpreter::with_capabilities(&Capabilities::none());
+    let res = interp.eval_str("(try* (prn 1) (catch* e (str \"caught: \" e)))").unwrap();
+    assert_eq!(res.pr_str(false), "caught: prn is not permitted: output is disabled");
+}
+
+#[test]
+fn files_under_a_directory() {
+    let dir = scratch("under");
+    let out = Rc::new(RefCell::new(String::new()));
+    let caps = Capabilities{files: FileAccess::ReadOnlyUnder(dir.clone()),
+                            output: Output::Capture(out.clone()), input: false};
+    let interp = Interpreter::with_capabilities(&caps);
+
+    let file = quoted(&dir.join("a.mal"));
+    let res = interp.eval_str(&format!("(load-file {})", file)).unwrap();
+    assert_eq!(res.pr_str(true), "\"a.mal done\"");
+    assert_eq!(interp.eval_str("x").unwrap().pr_str(true), "7");
+    let res = interp.eval_str(&format!("(slurp {})", file)).unwrap();
+    assert_eq!(res.pr_str(false), "(def! x 7)\n\"a.mal done\"\n");
+
+    let outside = dir.join("..").join("a.mal");
+    assert_denied(&interp, &format!("(slurp {})", quoted(&outside)),
+                  &format!("slurp is not permitted: {} is outside {}",
+                           outside.display(), dir.display()));
+    assert_denied(&interp, "(readline \"> \")", "readline is not permitted: input is disabled");
+}
+
+#[test]
+fn output_captured() {
+    let out = Rc::new(RefCell::new(String::new()));
+    let caps = Capabilities{files: FileAccess::Denied, output: Output::Capture(out.clone()),
+                            input: false};
+    let interp = Interpreter::with_capabilities(&caps);
+    interp.eval_str("(prn \"a\" 1) (println \"b\" 2)").unwrap();
+    assert_eq!(*out.borrow(), "\"a\" 1\nb 2\n");
+}
+
+#[test]
+fn everything_permitted() {
+    let dir = scratch("all");
+    let interp = Interpreter::with_capabilities(&Capabilities::all());
+    let res = interp.eval_str(&format!("(slurp {})", quoted(&dir.join("a.mal")))).unwrap();
+    assert_eq!(res.pr_str(false), "(def! x 7)\n\"a.mal done\"\n");
+}
@@ -0,0 +1,69 @@
+// The lock that values are kept in under the "threads" feature, which
+// is used like a RefCell
+
+#![cfg(feature = "threads")]
+
+extern crate mal;
+
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use std::time::Duration;
+
+use mal::sync::{Rc, RefCell};
+
+// Whether f panics, rather than returning or waiting forever
+fn panics<F: FnOnce()>(f: F) -> bool {
+    panic::catch_unwind(AssertUnwindSafe(f)).is_err()
+}
+
+#[test]
+fn borrowed_again_by_the_same_thread() {
+    let cell = RefCell::new(1);
+    {
+        let _r = cell.borrow();
+        assert_eq!(*cell.borrow(), 1);
+        assert!(panics(|| { cell.borrow_mut(); }));
+    }
+    {
+        let _w = cell.borrow_mut();
+        assert!(panics(|| { cell.borrow(); }));
+        assert!(panics(|| { cell.borrow_mut(); }));
+    }
+    *cell.borrow_mut() = 2;
+    assert_eq!(*cell.borrow(), 2);
+}
+
+#[test]
+fn borrowed_by_another_thread() {
+    let cell = Rc::new(RefCell::new(1));
+    let w = cell.borrow_mut();
+    let other = {
+        let cell = cell.clone();
+        thread::spawn(move || *cell.borrow())
+    };
+    thread::sleep(Duration::from_millis(50));
+    drop(w);
+    assert_eq!(other.join().unwrap(), 1);
+}
+
+// Threads that take two cells in the same order never wait on each
+// other forever (see the lock order RefCell documents)
+#[test]
+fn two_cells_in_the_same_order() {
+    let cells = Rc::new((RefCell::new(0), RefCell::new(0)));
+    let threads: Vec<_> = (0..4).map(|_| {
+        let cells = cells.clone();
+        thread::spawn(move || {
+            for _ in 0..10000 {
+                let mut a = cells.0.borrow_mut();
+                let mut b = cells.1.borrow_mut();
+                *a += 1;
+                *b += 1;
+            }
+        })
+    }).collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_eq!((*cells.0.borrow(), *cells.1.borrow()), (40000, 40000));
+}